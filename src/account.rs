@@ -0,0 +1,387 @@
+use axum::{
+    extract::{Form, Json, State},
+    response::{Html, Redirect},
+};
+use deadpool_redis::Connection;
+use redis::AsyncCommands;
+
+use crate::{
+    admin::HIDDEN_KEY,
+    digest, feed, notify,
+    session::{self, Member, Session},
+    subscription::{self, Subscription},
+    util, AppState, Error, User,
+};
+
+/// Set of the IDs of users who have hidden themselves from search6
+pub const OPTOUT_KEY: &str = "privacy:optout";
+
+pub async fn is_opted_out(redis: &mut Connection, id: u64) -> Result<bool, Error> {
+    Ok(redis.sismember(OPTOUT_KEY, id).await?)
+}
+
+/// Removes everything the sync has cached about a user, and any alerts they set or are watched
+/// by. Unless they are also opted out, the next sync pass will bring them back.
+pub async fn purge_user(redis: &mut Connection, id: u64) -> Result<(), Error> {
+    let user: Option<String> = redis.get(format!("user.id:{id}")).await?;
+    let mut keys = vec![format!("user.id:{id}"), notify::announced_key(id)];
+    if let Some(user) = user {
+        let user: User = serde_json::from_str(&user)?;
+        let rank_key = format!("user.rank:{}", user.rank);
+        let ranked: Option<u64> = redis.get(&rank_key).await?;
+        keys.push(format!("user.name:{}", user.human_identifier()));
+        if ranked == Some(id) {
+            keys.push(rank_key);
+        }
+    }
+    redis.del::<_, ()>(keys).await?;
+    digest::forget(redis, id).await?;
+    feed::forget(redis, id).await?;
+    subscription::remove_all(redis, id).await?;
+    Ok(())
+}
+
+const SLUG_MIN_LEN: usize = 3;
+const SLUG_MAX_LEN: usize = 32;
+/// Slugs that would be confusing, or that we might want as routes some day
+const RESERVED_SLUGS: &[&str] = &[
+    "about",
+    "account",
+    "admin",
+    "api",
+    "badge",
+    "card",
+    "events",
+    "feed",
+    "help",
+    "leaderboard",
+    "login",
+    "logout",
+    "me",
+    "mee6",
+    "metrics",
+    "mod",
+    "moderator",
+    "null",
+    "privacy",
+    "search6",
+    "settings",
+    "staff",
+    "undefined",
+    "valk",
+];
+/// Blocked anywhere in a slug, so they can't be hidden by running words together
+const BLOCKED_SLUG_WORDS: &[&str] = &[
+    "bitch", "cock", "cunt", "dick", "fag", "fuck", "nazi", "nigg", "porn", "pussy", "rape",
+    "retard", "shit", "slut", "whore",
+];
+/// Innocent words that happen to contain a blocked one, which are taken out before checking
+const ALLOWED_SLUG_WORDS: &[&str] = &[
+    "babcock",
+    "cockatoo",
+    "cockpit",
+    "cockroach",
+    "cocktail",
+    "dickens",
+    "dickinson",
+    "dickson",
+    "drape",
+    "grape",
+    "hancock",
+    "niggle",
+    "parapet",
+    "peacock",
+    "scrape",
+    "scunthorpe",
+    "shuttlecock",
+    "therapist",
+    "trapeze",
+];
+
+/// Returns the slug `id` has claimed, if any
+pub async fn get_slug(redis: &mut Connection, id: u64) -> Result<Option<String>, Error> {
+    Ok(redis.get(format!("user.slugof:{id}")).await?)
+}
+
+fn validate_slug(slug: &str) -> Result<(), Error> {
+    if !(SLUG_MIN_LEN..=SLUG_MAX_LEN).contains(&slug.len()) {
+        return Err(Error::InvalidSlug("Slugs must be 3 to 32 characters long"));
+    }
+    if !slug.starts_with(|c: char| c.is_ascii_lowercase()) {
+        return Err(Error::InvalidSlug("Slugs must start with a letter"));
+    }
+    if !slug
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+    {
+        return Err(Error::InvalidSlug(
+            "Slugs may only contain lowercase letters, numbers, dashes and underscores",
+        ));
+    }
+    if RESERVED_SLUGS.contains(&slug) {
+        return Err(Error::InvalidSlug("That slug is reserved"));
+    }
+    // Allowed words become separators, so they can't be used to join two halves of a blocked one
+    let checked = ALLOWED_SLUG_WORDS
+        .iter()
+        .fold(slug.to_string(), |slug, word| slug.replace(word, "-"));
+    if BLOCKED_SLUG_WORDS.iter().any(|word| checked.contains(word)) {
+        return Err(Error::InvalidSlug("That slug is not allowed"));
+    }
+    Ok(())
+}
+
+/// Claims `slug` for `id`, releasing whatever slug they had before.
+async fn claim_slug(redis: &mut Connection, id: u64, slug: &str) -> Result<(), Error> {
+    let slug_key = format!("user.slug:{slug}");
+    let claimed: bool = redis.set_nx(&slug_key, id).await?;
+    if !claimed {
+        let owner: Option<u64> = redis.get(&slug_key).await?;
+        if owner != Some(id) {
+            return Err(Error::SlugTaken);
+        }
+    }
+    let old: Option<String> = redis.getset(format!("user.slugof:{id}"), slug).await?;
+    if let Some(old) = old.filter(|old| old != slug) {
+        redis.del::<_, ()>(format!("user.slug:{old}")).await?;
+    }
+    Ok(())
+}
+
+/// Releases the slug `id` has claimed, if any.
+pub async fn release_slug(redis: &mut Connection, id: u64) -> Result<(), Error> {
+    let old: Option<String> = redis.get_del(format!("user.slugof:{id}")).await?;
+    if let Some(old) = old {
+        redis.del::<_, ()>(format!("user.slug:{old}")).await?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn account(
+    State(state): State<AppState>,
+    session: Session,
+) -> Result<Html<String>, Error> {
+    let mut redis = state.redis.get().await?;
+    let mut ctx = tera::Context::new();
+    ctx.insert("root_url", &*state.root_url);
+    ctx.insert("user_id", &session.user_id.to_string());
+    ctx.insert(
+        "opted_out",
+        &is_opted_out(&mut redis, session.user_id).await?,
+    );
+    ctx.insert("slug", &get_slug(&mut redis, session.user_id).await?);
+    ctx.insert("guild_check", &state.guild_check.is_some());
+    ctx.insert("member", &session.member);
+    ctx.insert("admin", &state.admins.contains(&session.user_id));
+    ctx.insert("alerts", &state.webhook.is_some());
+    ctx.insert(
+        "subscriptions",
+        &subscription::views(&mut redis, session.user_id).await?,
+    );
+    if session.member {
+        // Users who haven't synced yet or have opted out just don't get stats
+        if let Ok(user) = util::get_user(&state, session.user_id.to_string(), true).await {
+            ctx.insert("stats", &MemberStats::from(user));
+        }
+    }
+    Ok(Html(state.tera.render("account.html", &ctx)?))
+}
+
+/// Stats only shown to the user themselves, and only if they are verified to be in the server
+#[derive(serde::Serialize)]
+pub struct MemberStats {
+    level: u64,
+    level_progress: f64,
+    xp: u64,
+    xp_to_next_level: u64,
+    rank: i64,
+    message_count: Option<u64>,
+    last_updated: Option<i64>,
+}
+
+impl From<User> for MemberStats {
+    fn from(user: User) -> Self {
+        let level_info = mee6::LevelInfo::new(user.xp);
+        let next_level = mee6::xp_needed_for_level(level_info.level() + 1);
+        Self {
+            level: level_info.level(),
+            level_progress: level_info.percentage(),
+            xp: user.xp,
+            xp_to_next_level: next_level.saturating_sub(level_info.xp()),
+            rank: user.rank,
+            message_count: user.message_count,
+            last_updated: user.last_updated,
+        }
+    }
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn member_stats(
+    State(state): State<AppState>,
+    Member(session): Member,
+) -> Result<Json<MemberStats>, Error> {
+    let user = util::get_user(&state, session.user_id.to_string(), true).await?;
+    Ok(Json(MemberStats::from(user)))
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn set_privacy(
+    State(state): State<AppState>,
+    session: Session,
+    Form(form): Form<PrivacyForm>,
+) -> Result<Redirect, Error> {
+    let mut redis = state.redis.get().await?;
+    if form.opt_out {
+        redis.sadd::<_, _, ()>(OPTOUT_KEY, session.user_id).await?;
+        purge_user(&mut redis, session.user_id).await?;
+        info!("User {} opted out", session.user_id);
+    } else {
+        redis.srem::<_, _, ()>(OPTOUT_KEY, session.user_id).await?;
+        info!("User {} opted back in", session.user_id);
+    }
+    Ok(Redirect::to("/account"))
+}
+
+#[derive(serde::Deserialize)]
+pub struct PrivacyForm {
+    opt_out: bool,
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn set_slug(
+    State(state): State<AppState>,
+    session: Session,
+    Form(form): Form<SlugForm>,
+) -> Result<Redirect, Error> {
+    let mut redis = state.redis.get().await?;
+    let slug = form.slug.trim().to_ascii_lowercase();
+    if slug.is_empty() {
+        release_slug(&mut redis, session.user_id).await?;
+        info!("User {} released their slug", session.user_id);
+    } else {
+        validate_slug(&slug)?;
+        claim_slug(&mut redis, session.user_id, &slug).await?;
+        info!("User {} claimed slug {slug}", session.user_id);
+    }
+    Ok(Redirect::to("/account"))
+}
+
+#[derive(serde::Deserialize)]
+pub struct SlugForm {
+    slug: String,
+}
+
+/// Everything search6 stores about a user
+#[derive(serde::Serialize)]
+pub struct DataExport {
+    user_id: u64,
+    user: Option<User>,
+    slug: Option<String>,
+    opted_out: bool,
+    hidden_by_admin: bool,
+    active_sessions: usize,
+    announced_milestones: Vec<u64>,
+    feed_entries: Vec<feed::FeedEntry>,
+    subscriptions: Vec<Subscription>,
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn export(
+    State(state): State<AppState>,
+    session: Session,
+) -> Result<([(&'static str, String); 2], Json<DataExport>), Error> {
+    let id = session.user_id;
+    let mut redis = state.redis.get().await?;
+    let user: Option<String> = redis.get(format!("user.id:{id}")).await?;
+    let user = user.map(|user| serde_json::from_str(&user)).transpose()?;
+    let active_sessions: usize = redis.scard(format!("user.sessions:{id}")).await?;
+    let mut announced_milestones: Vec<u64> = redis.smembers(notify::announced_key(id)).await?;
+    announced_milestones.sort_unstable();
+    let export = DataExport {
+        user_id: id,
+        user,
+        slug: get_slug(&mut redis, id).await?,
+        opted_out: is_opted_out(&mut redis, id).await?,
+        hidden_by_admin: redis.sismember(HIDDEN_KEY, id).await?,
+        active_sessions,
+        announced_milestones,
+        feed_entries: feed::entries_for(&mut redis, id).await?,
+        subscriptions: subscription::get_subscriptions(&mut redis, id).await?,
+    };
+    Ok((
+        [
+            ("Content-Type", "application/json".to_string()),
+            (
+                "Content-Disposition",
+                format!("attachment; filename=\"search6-{id}.json\""),
+            ),
+        ],
+        Json(export),
+    ))
+}
+
+/// Deletes everything search6 stores about a user, and keeps the sync from bringing it back.
+#[allow(clippy::missing_errors_doc)]
+pub async fn delete(
+    State(state): State<AppState>,
+    session: Session,
+    Form(form): Form<DeleteForm>,
+) -> Result<([(&'static str, String); 1], Redirect), Error> {
+    if !form.confirm {
+        return Err(Error::DeletionNotConfirmed);
+    }
+    let id = session.user_id;
+    let mut redis = state.redis.get().await?;
+    // Opt out first, so a sync pass running right now can't put anything back
+    redis.sadd::<_, _, ()>(OPTOUT_KEY, id).await?;
+    purge_user(&mut redis, id).await?;
+    release_slug(&mut redis, id).await?;
+    session::destroy_all(&mut redis, id).await?;
+    info!("User {id} deleted their data");
+    Ok((
+        [("Set-Cookie", session::clear_cookie(&state))],
+        Redirect::to("/"),
+    ))
+}
+
+#[derive(serde::Deserialize)]
+pub struct DeleteForm {
+    #[serde(default)]
+    confirm: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_profanity_anywhere() {
+        for slug in [
+            "fuck",
+            "fuckyou",
+            "bigdick",
+            "shitlord",
+            "xx_cunt_xx",
+            "grapefuck",
+            "cocktailshit",
+        ] {
+            assert!(validate_slug(slug).is_err(), "{slug} should be blocked");
+        }
+    }
+
+    #[test]
+    fn allows_innocent_words() {
+        for slug in [
+            "valkyrie",
+            "scunthorpe",
+            "cocktail-hour",
+            "grapevine",
+            "the_therapist",
+            "peacock42",
+            "dickens-fan",
+        ] {
+            assert!(validate_slug(slug).is_ok(), "{slug} should be allowed");
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::{collections::HashSet, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{Form, FromRequestParts, State},
+    http::request::Parts,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use redis::AsyncCommands;
+
+use crate::{
+    account::{self, OPTOUT_KEY},
+    notify, queue,
+    reload::{PAGE_KEY, RANK_KEY},
+    session::Session,
+    util, AppState, Error,
+};
+
+/// Set of the IDs of users an admin has hidden from search6
+pub const HIDDEN_KEY: &str = "admin:hidden";
+
+/// A logged-in user whose ID is in `ADMIN_IDS`
+pub struct Admin(pub Session);
+
+pub fn get_admins() -> Arc<HashSet<u64>> {
+    let admins = std::env::var("ADMIN_IDS").map_or_else(
+        |_| HashSet::new(),
+        |ids| {
+            ids.split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(|id| {
+                    id.parse()
+                        .expect("Expected comma-separated user IDs in ADMIN_IDS")
+                })
+                .collect()
+        },
+    );
+    Arc::new(admins)
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Admin {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Error> {
+        let session = Session::from_request_parts(parts, state).await?;
+        if !state.admins.contains(&session.user_id) {
+            return Err(Error::NotAdmin);
+        }
+        Ok(Self(session))
+    }
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn dashboard(
+    State(state): State<AppState>,
+    Admin(session): Admin,
+) -> Result<Html<String>, Error> {
+    let mut redis = state.redis.get().await?;
+    let (page, rank): (Option<i64>, Option<i64>) = redis.get(&[PAGE_KEY, RANK_KEY]).await?;
+    let mut hidden: Vec<u64> = redis.smembers(HIDDEN_KEY).await?;
+    hidden.sort_unstable();
+    let opted_out: u64 = redis.scard(OPTOUT_KEY).await?;
+    let pending = queue::pending(&mut redis).await?;
+    let dead: Vec<DeadLetter> = queue::dead_letters(&mut redis)
+        .await?
+        .into_iter()
+        .map(|job| DeadLetter {
+            description: job.delivery.description(),
+            id: job.id,
+            attempts: job.attempts,
+            error: job.last_error.unwrap_or_default(),
+        })
+        .collect();
+    let mut ctx = tera::Context::new();
+    ctx.insert("root_url", &*state.root_url);
+    ctx.insert("user_id", &session.user_id.to_string());
+    ctx.insert("page", &page);
+    ctx.insert("rank", &rank);
+    ctx.insert(
+        "hidden",
+        &hidden.iter().map(ToString::to_string).collect::<Vec<_>>(),
+    );
+    ctx.insert("opted_out", &opted_out);
+    ctx.insert("webhook", &state.notifier.enabled(state.webhook.as_ref()));
+    ctx.insert(
+        "milestones",
+        &state.notifier.milestone_levels().collect::<Vec<_>>(),
+    );
+    ctx.insert(
+        "rank_brackets",
+        &state
+            .notifier
+            .rank_brackets
+            .iter()
+            .map(|t| t.value)
+            .collect::<Vec<_>>(),
+    );
+    ctx.insert(
+        "overtakes",
+        &state
+            .notifier
+            .overtakes
+            .iter()
+            .map(|t| t.value)
+            .collect::<Vec<_>>(),
+    );
+    ctx.insert(
+        "sinks",
+        &state
+            .notifier
+            .sinks
+            .iter()
+            .map(|sink| &sink.url)
+            .collect::<Vec<_>>(),
+    );
+    ctx.insert("pending", &pending);
+    ctx.insert("dead", &dead);
+    Ok(Html(state.tera.render("admin.html", &ctx)?))
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn resync(
+    State(state): State<AppState>,
+    Admin(session): Admin,
+) -> Result<Redirect, Error> {
+    state
+        .redis
+        .get()
+        .await?
+        .mset::<_, _, ()>(&[(PAGE_KEY, 0), (RANK_KEY, 1)])
+        .await?;
+    info!("Admin {} restarted the sync", session.user_id);
+    Ok(Redirect::to("/admin"))
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn set_hidden(
+    State(state): State<AppState>,
+    Admin(session): Admin,
+    Form(form): Form<HideForm>,
+) -> Result<Redirect, Error> {
+    let id: u64 = form.id.trim().parse()?;
+    let mut redis = state.redis.get().await?;
+    if form.hidden {
+        redis.sadd::<_, _, ()>(HIDDEN_KEY, id).await?;
+        account::purge_user(&mut redis, id).await?;
+        info!("Admin {} hid user {id}", session.user_id);
+    } else {
+        redis.srem::<_, _, ()>(HIDDEN_KEY, id).await?;
+        info!("Admin {} unhid user {id}", session.user_id);
+    }
+    Ok(Redirect::to("/admin"))
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn purge(
+    State(state): State<AppState>,
+    Admin(session): Admin,
+    Form(form): Form<UserForm>,
+) -> Result<Redirect, Error> {
+    let id: u64 = form.id.trim().parse()?;
+    account::purge_user(&mut state.redis.get().await?, id).await?;
+    info!("Admin {} purged cached data for user {id}", session.user_id);
+    Ok(Redirect::to("/admin"))
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn test_webhook(
+    State(state): State<AppState>,
+    Admin(session): Admin,
+    Form(form): Form<TestForm>,
+) -> Result<Response, Error> {
+    let user = util::get_user(&state, form.id.trim().to_string(), false).await?;
+    info!(
+        "Admin {} sent a test webhook for user {}",
+        session.user_id, user.id
+    );
+    let message = notify::test_announcement(&state, user, form.dry_run).await?;
+    if form.dry_run {
+        return Ok(message.to_string().into_response());
+    }
+    Ok(Redirect::to("/admin").into_response())
+}
+
+/// `search6 test-webhook <user> [--dry-run]`, the command-line version of [`test_webhook`]
+pub async fn test_webhook_command(
+    state: &AppState,
+    id: String,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let user = util::get_user(state, id, false).await?;
+    let message = notify::test_announcement(state, user, dry_run).await?;
+    println!("{message}");
+    Ok(())
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn replay_notification(
+    State(state): State<AppState>,
+    Admin(session): Admin,
+    Form(form): Form<JobForm>,
+) -> Result<Redirect, Error> {
+    queue::replay(&mut state.redis.get().await?, &form.id).await?;
+    info!(
+        "Admin {} replayed notification {}",
+        session.user_id, form.id
+    );
+    Ok(Redirect::to("/admin"))
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn discard_notification(
+    State(state): State<AppState>,
+    Admin(session): Admin,
+    Form(form): Form<JobForm>,
+) -> Result<Redirect, Error> {
+    queue::discard(&mut state.redis.get().await?, &form.id).await?;
+    info!(
+        "Admin {} discarded notification {}",
+        session.user_id, form.id
+    );
+    Ok(Redirect::to("/admin"))
+}
+
+#[derive(serde::Serialize)]
+struct DeadLetter {
+    id: String,
+    description: String,
+    attempts: u32,
+    error: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct JobForm {
+    id: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct HideForm {
+    id: String,
+    hidden: bool,
+}
+
+#[derive(serde::Deserialize)]
+pub struct TestForm {
+    id: String,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(serde::Deserialize)]
+pub struct UserForm {
+    id: String,
+}
@@ -0,0 +1,165 @@
+use axum::extract::{Query, State};
+use resvg::tiny_skia::{
+    FillRule, FilterQuality, IntSize, Mask, PathBuilder, Pixmap, PixmapPaint, Transform,
+};
+
+use crate::{handlers::SubmitQuery, util, AppState, Error, User};
+
+/// Animations longer than this are cut off, so a huge avatar can't stall the renderer.
+const MAX_FRAMES: usize = 60;
+const MAX_AVATAR_BYTES: usize = 4 * 1024 * 1024;
+const MAX_AVATAR_DIMENSION: u16 = 512;
+/// Frame delays (in hundredths of a second) below this are treated as this, like browsers do.
+const MIN_FRAME_DELAY: u16 = 2;
+/// `NeuQuant` sampling factor: 1 is best quality, 30 is fastest.
+const QUANTIZE_SPEED: i32 = 20;
+
+// The avatar's position on xpd-rank-card's card.svg
+const AVATAR_X: f32 = 30.0;
+const AVATAR_Y: f32 = 25.0;
+const AVATAR_SIZE: f32 = 90.0;
+
+/// A 1x1 fully transparent PNG, so the card renders with an empty space for the avatar.
+const BLANK_AVATAR: &str = "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNkYPhfDwAChwGA60e6kgAAAABJRU5ErkJggg==";
+
+struct AvatarFrame {
+    pixmap: Pixmap,
+    delay: u16,
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn fetch_animated_card(
+    State(state): State<AppState>,
+    Query(query): Query<SubmitQuery>,
+) -> Result<([(&'static str, &'static str); 1], Vec<u8>), Error> {
+    let Some(id) = query.id else {
+        return Err(Error::NoId);
+    };
+    let user = util::get_user(&state, id, query.userexists).await?;
+    Ok((
+        [("Content-Type", "image/gif")],
+        render(&state, &user).await?,
+    ))
+}
+
+/// Renders a GIF rank card, with every frame of the user's animated avatar composited on.
+/// Static avatars produce a single-frame GIF.
+pub async fn render(state: &AppState, user: &User) -> Result<Vec<u8>, Error> {
+    let avatar = get_avatar_bytes(state, user).await?;
+    let _permit = state.render_queue.acquire().await?;
+    let card = state
+        .svg
+        .render(util::card_context(user, BLANK_AVATAR.to_string()))
+        .await?;
+    tokio::task::spawn_blocking(move || {
+        let card = Pixmap::decode_png(&card)?;
+        let frames = decode_avatar(&avatar)?;
+        encode(&card, &frames)
+    })
+    .await?
+}
+
+async fn get_avatar_bytes(state: &AppState, user: &User) -> Result<Vec<u8>, Error> {
+    let url = util::get_avatar_url(user.id, user.avatar.as_deref(), true);
+    let resp = state.http.get(url).send().await?.error_for_status()?;
+    if resp
+        .content_length()
+        .is_some_and(|len| len > MAX_AVATAR_BYTES as u64)
+    {
+        return Err(Error::AvatarTooLarge);
+    }
+    let bytes = resp.bytes().await?;
+    if bytes.len() > MAX_AVATAR_BYTES {
+        return Err(Error::AvatarTooLarge);
+    }
+    Ok(bytes.to_vec())
+}
+
+fn decode_avatar(data: &[u8]) -> Result<Vec<AvatarFrame>, Error> {
+    if !data.starts_with(b"GIF8") {
+        let pixmap = Pixmap::decode_png(data)?;
+        return Ok(vec![AvatarFrame { pixmap, delay: 0 }]);
+    }
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(data)?;
+    let (width, height) = (decoder.width(), decoder.height());
+    if width > MAX_AVATAR_DIMENSION || height > MAX_AVATAR_DIMENSION {
+        return Err(Error::AvatarTooLarge);
+    }
+    let size = IntSize::from_wh(width.into(), height.into()).ok_or(Error::PixmapCreation)?;
+    // GIF frames only cover the part of the image that changed, so keep a running canvas.
+    // GIF pixels are either fully opaque or fully transparent, so this is already premultiplied.
+    let mut canvas = vec![0u8; usize::from(width) * usize::from(height) * 4];
+    let mut frames = Vec::new();
+    while let Some(frame) = decoder.read_next_frame()? {
+        if frames.len() >= MAX_FRAMES {
+            break;
+        }
+        let previous = (frame.dispose == gif::DisposalMethod::Previous).then(|| canvas.clone());
+        draw_frame(&mut canvas, width, frame, false);
+        frames.push(AvatarFrame {
+            pixmap: Pixmap::from_vec(canvas.clone(), size).ok_or(Error::PixmapCreation)?,
+            delay: frame.delay,
+        });
+        match frame.dispose {
+            gif::DisposalMethod::Background => draw_frame(&mut canvas, width, frame, true),
+            gif::DisposalMethod::Previous => canvas = previous.unwrap_or(canvas),
+            _ => {}
+        }
+    }
+    Ok(frames)
+}
+
+/// Copies the frame's opaque pixels onto the canvas, or clears the area it covers if `clear` is set.
+fn draw_frame(canvas: &mut [u8], canvas_width: u16, frame: &gif::Frame, clear: bool) {
+    let canvas_width = usize::from(canvas_width);
+    let canvas_height = canvas.len() / 4 / canvas_width;
+    let frame_width = usize::from(frame.width);
+    for (i, pixel) in frame.buffer.chunks_exact(4).enumerate() {
+        let x = usize::from(frame.left) + i % frame_width;
+        let y = usize::from(frame.top) + i / frame_width;
+        if x >= canvas_width || y >= canvas_height {
+            continue;
+        }
+        let offset = (y * canvas_width + x) * 4;
+        if clear {
+            canvas[offset..offset + 4].fill(0);
+        } else if pixel[3] != 0 {
+            canvas[offset..offset + 4].copy_from_slice(pixel);
+        }
+    }
+}
+
+fn encode(card: &Pixmap, frames: &[AvatarFrame]) -> Result<Vec<u8>, Error> {
+    let mut mask = Mask::new(card.width(), card.height()).ok_or(Error::PixmapCreation)?;
+    let radius = AVATAR_SIZE / 2.0;
+    let circle = PathBuilder::from_circle(AVATAR_X + radius, AVATAR_Y + radius, radius)
+        .ok_or(Error::PixmapCreation)?;
+    mask.fill_path(&circle, FillRule::Winding, true, Transform::identity());
+    let paint = PixmapPaint {
+        quality: FilterQuality::Bilinear,
+        ..PixmapPaint::default()
+    };
+    let width = u16::try_from(card.width()).map_err(|_| Error::PixmapCreation)?;
+    let height = u16::try_from(card.height()).map_err(|_| Error::PixmapCreation)?;
+    let mut out = Vec::new();
+    let mut encoder = gif::Encoder::new(&mut out, width, height, &[])?;
+    encoder.set_repeat(gif::Repeat::Infinite)?;
+    for avatar in frames {
+        let mut composite = card.clone();
+        #[allow(clippy::cast_precision_loss)]
+        let transform = Transform::from_scale(
+            AVATAR_SIZE / avatar.pixmap.width() as f32,
+            AVATAR_SIZE / avatar.pixmap.height() as f32,
+        )
+        .post_translate(AVATAR_X, AVATAR_Y);
+        composite.draw_pixmap(0, 0, avatar.pixmap.as_ref(), &paint, transform, Some(&mask));
+        let mut pixels = composite.take();
+        let mut frame = gif::Frame::from_rgba_speed(width, height, &mut pixels, QUANTIZE_SPEED);
+        frame.delay = avatar.delay.max(MIN_FRAME_DELAY);
+        encoder.write_frame(&frame)?;
+    }
+    drop(encoder);
+    Ok(out)
+}
@@ -0,0 +1,427 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone, Utc, Weekday};
+use deadpool_redis::Connection;
+use mee6::LevelInfo;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use twilight_model::{
+    http::attachment::Attachment,
+    id::{marker::ChannelMarker, Id},
+};
+use twilight_util::builder::embed::{EmbedBuilder, EmbedFieldBuilder, ImageSource};
+
+use crate::{
+    account::OPTOUT_KEY,
+    admin::HIDDEN_KEY,
+    queue::{self, Delivery},
+    util::WebhookState,
+    AppState, Error, User,
+};
+
+/// Hash of user ID to the highest level they reached this period
+const LEVELS_KEY: &str = "digest:levels";
+/// Sorted set of user IDs by XP gained this period
+const GAINS_KEY: &str = "digest:xp";
+/// Set of user IDs that entered the top N this period
+const TOP_KEY: &str = "digest:top";
+/// The last digest slot, in milliseconds since the epoch, that has been claimed by a replica
+const LAST_KEY: &str = "digest:last";
+
+/// How many names each section of the digest lists before summarising the rest
+const SECTION_LENGTH: usize = 10;
+const GAINERS: usize = 5;
+/// Discord's limit on the length of an embed field's value
+const FIELD_LENGTH: usize = 1024;
+
+// Claims the slot ARGV[1], unless it (or a later one) already has been. The very first slot is
+// only recorded, so that deploying doesn't immediately post a digest of nothing.
+const CLAIM_SCRIPT: &str = r"
+local last = redis.call('GET', KEYS[1])
+if not last then
+    redis.call('SET', KEYS[1], ARGV[1])
+    return 0
+end
+if tonumber(last) < tonumber(ARGV[1]) then
+    redis.call('SET', KEYS[1], ARGV[1])
+    return 1
+end
+return 0
+";
+
+// Returns and clears everything recorded this period, keeping the top ARGV[1] gainers
+const TAKE_SCRIPT: &str = r"
+local levels = redis.call('HGETALL', KEYS[1])
+local gains = redis.call('ZREVRANGE', KEYS[2], 0, tonumber(ARGV[1]) - 1, 'WITHSCORES')
+local top = redis.call('SMEMBERS', KEYS[3])
+redis.call('DEL', KEYS[1], KEYS[2], KEYS[3])
+return {levels, gains, top}
+";
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Daily,
+    Weekly,
+}
+
+/// The `digest` section of `NOTIFY_CONFIG`
+#[derive(Deserialize)]
+pub struct DigestConfig {
+    period: Period,
+    /// `HH:MM`, in UTC
+    #[serde(default = "default_time")]
+    time: String,
+    /// Day of the week weekly digests are posted on
+    weekday: Option<String>,
+    /// Size of the leaderboard image, and of the top N that entering gets a mention
+    #[serde(default = "default_top")]
+    top: i64,
+    webhook: Option<String>,
+    thread_id: Option<Id<ChannelMarker>>,
+    /// Keep announcing every event as it happens too, instead of only in the digest
+    #[serde(default)]
+    announce_individually: bool,
+}
+
+fn default_time() -> String {
+    "00:00".to_string()
+}
+
+const fn default_top() -> i64 {
+    10
+}
+
+/// When digests are posted, and where
+pub struct Schedule {
+    period: Period,
+    time: NaiveTime,
+    weekday: Weekday,
+    pub top: i64,
+    /// Falls back to `WEBHOOK` when unset
+    webhook: Option<WebhookState>,
+    pub announce_individually: bool,
+}
+
+impl Schedule {
+    pub fn new(config: DigestConfig, client: Arc<twilight_http::Client>) -> Self {
+        let time = NaiveTime::parse_from_str(&config.time, "%H:%M")
+            .expect("Expected HH:MM in the digest time");
+        let weekday = config.weekday.map_or(Weekday::Mon, |weekday| {
+            weekday
+                .parse()
+                .expect("Expected a day of the week in the digest weekday")
+        });
+        Self {
+            period: config.period,
+            time,
+            weekday,
+            top: config.top.clamp(1, 25),
+            webhook: config
+                .webhook
+                .map(|url| crate::util::parse_webhook(client, &url, config.thread_id)),
+            announce_individually: config.announce_individually,
+        }
+    }
+
+    pub const fn webhook(&self) -> Option<&WebhookState> {
+        self.webhook.as_ref()
+    }
+
+    /// The most recent time at or before `now` that a digest was due
+    fn last_slot(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = Utc.from_utc_datetime(&now.date_naive().and_time(self.time));
+        let (slot, length) = match self.period {
+            Period::Daily => (today, chrono::Duration::days(1)),
+            Period::Weekly => {
+                let days_since = (7 + now.weekday().num_days_from_monday()
+                    - self.weekday.num_days_from_monday())
+                    % 7;
+                (
+                    today - chrono::Duration::days(days_since.into()),
+                    chrono::Duration::weeks(1),
+                )
+            }
+        };
+        if slot > now {
+            slot - length
+        } else {
+            slot
+        }
+    }
+}
+
+/// A period's worth of activity, ready to be posted
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Digest {
+    period: Period,
+    top: i64,
+    /// Users who levelled up, and the level they ended on
+    level_ups: Vec<(User, u64)>,
+    /// The biggest XP gainers, and how much they gained
+    gainers: Vec<(User, u64)>,
+    new_top: Vec<User>,
+}
+
+impl Digest {
+    pub const fn description(&self) -> &'static str {
+        match self.period {
+            Period::Daily => "Daily digest",
+            Period::Weekly => "Weekly digest",
+        }
+    }
+}
+
+/// Note what changed between a page's old and new users, for the next digest
+pub async fn record(
+    redis: &mut Connection,
+    schedule: &Schedule,
+    old_users: &HashMap<u64, User>,
+    new_users: &HashMap<u64, User>,
+) -> Result<(), Error> {
+    let mut pipe = redis::pipe();
+    for (id, new_user) in new_users {
+        let Some(old_user) = old_users.get(id) else {
+            continue;
+        };
+        if new_user.xp > old_user.xp {
+            pipe.zincr(GAINS_KEY, id, new_user.xp - old_user.xp)
+                .ignore();
+        }
+        let new_level = LevelInfo::new(new_user.xp).level();
+        if new_level > LevelInfo::new(old_user.xp).level() {
+            pipe.hset(LEVELS_KEY, id, new_level).ignore();
+        }
+        if old_user.rank > schedule.top && new_user.rank <= schedule.top {
+            pipe.sadd(TOP_KEY, id).ignore();
+        }
+    }
+    pipe.query_async::<_, ()>(redis).await?;
+    Ok(())
+}
+
+/// Drop a user from the digest being collected
+pub async fn forget(redis: &mut Connection, id: u64) -> Result<(), Error> {
+    redis::pipe()
+        .hdel(LEVELS_KEY, id)
+        .ignore()
+        .zrem(GAINS_KEY, id)
+        .ignore()
+        .srem(TOP_KEY, id)
+        .ignore()
+        .query_async::<_, ()>(redis)
+        .await?;
+    Ok(())
+}
+
+pub async fn digest_loop(state: AppState) {
+    if state.notifier.digest.is_none() {
+        return;
+    }
+    let mut timer = tokio::time::interval(Duration::from_mins(1));
+    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        timer.tick().await;
+        if let Err(e) = run(&state).await {
+            error!("{e:?}");
+        }
+    }
+}
+
+/// Queue the digest for the latest slot, if no replica has yet
+async fn run(state: &AppState) -> Result<(), Error> {
+    let Some(schedule) = &state.notifier.digest else {
+        return Ok(());
+    };
+    let slot = schedule.last_slot(Utc::now()).timestamp_millis();
+    let mut redis = state.redis.get().await?;
+    let claimed: bool = redis::cmd("EVAL")
+        .arg(CLAIM_SCRIPT)
+        .arg(1)
+        .arg(LAST_KEY)
+        .arg(slot)
+        .query_async(&mut redis)
+        .await?;
+    if !claimed {
+        return Ok(());
+    }
+    debug!("Posting the digest for {slot}");
+    let digest = take(&mut redis, schedule).await?;
+    queue::enqueue(&mut redis, vec![Delivery::Digest { digest }]).await
+}
+
+/// Each user's level, XP gained, and the users that entered the top N
+type Recorded = (Vec<(u64, u64)>, Vec<(u64, f64)>, Vec<u64>);
+
+async fn take(redis: &mut Connection, schedule: &Schedule) -> Result<Digest, Error> {
+    let (levels, gains, top): Recorded = redis::cmd("EVAL")
+        .arg(TAKE_SCRIPT)
+        .arg(3)
+        .arg(LEVELS_KEY)
+        .arg(GAINS_KEY)
+        .arg(TOP_KEY)
+        .arg(GAINERS)
+        .query_async(redis)
+        .await?;
+    let ids: HashSet<u64> = levels
+        .iter()
+        .map(|(id, _)| *id)
+        .chain(gains.iter().map(|(id, _)| *id))
+        .chain(top.iter().copied())
+        .collect();
+    let users = get_users(redis, ids).await?;
+    let mut level_ups: Vec<(User, u64)> = levels
+        .into_iter()
+        .filter_map(|(id, level)| Some((users.get(&id)?.clone(), level)))
+        .collect();
+    level_ups.sort_unstable_by_key(|(user, level)| (std::cmp::Reverse(*level), user.rank));
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let gainers = gains
+        .into_iter()
+        .filter_map(|(id, xp)| Some((users.get(&id)?.clone(), xp as u64)))
+        .collect();
+    let mut new_top: Vec<User> = top
+        .into_iter()
+        .filter_map(|id| users.get(&id).cloned())
+        .collect();
+    new_top.sort_unstable_by_key(|user| user.rank);
+    Ok(Digest {
+        period: schedule.period,
+        top: schedule.top,
+        level_ups,
+        gainers,
+        new_top,
+    })
+}
+
+/// The current records of users that haven't since opted out or been hidden
+async fn get_users(redis: &mut Connection, ids: HashSet<u64>) -> Result<HashMap<u64, User>, Error> {
+    if ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let hidden: HashSet<u64> = redis.sunion(&[OPTOUT_KEY, HIDDEN_KEY]).await?;
+    let user_keys: Vec<String> = ids
+        .iter()
+        .filter(|id| !hidden.contains(id))
+        .map(|id| format!("user.id:{id}"))
+        .collect();
+    if user_keys.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let users: Vec<Option<String>> = redis.mget(user_keys).await?;
+    Ok(users
+        .into_iter()
+        .flatten()
+        .filter_map(|user| serde_json::from_str::<User>(&user).ok())
+        .map(|user| (user.id, user))
+        .collect())
+}
+
+/// Post a digest, with the current top of the leaderboard
+pub async fn send(state: &AppState, digest: &Digest) -> Result<(), Error> {
+    let notifier = &state.notifier;
+    let webhook = notifier
+        .digest
+        .as_ref()
+        .and_then(Schedule::webhook)
+        .or(state.webhook.as_ref())
+        .ok_or(Error::WebhookDisabled)?;
+    let users = crate::leaderboard::get_leaderboard(state, 1, digest.top).await?;
+    let leaderboard = state
+        .render_queue
+        .render_leaderboard(&state.leaderboard, &state.root_url, &users)
+        .await?;
+    let mut embed = EmbedBuilder::new()
+        .title(digest.description())
+        .image(ImageSource::attachment("leaderboard.png")?);
+    if digest.level_ups.is_empty() && digest.gainers.is_empty() && digest.new_top.is_empty() {
+        embed = embed.description("Nothing changed on the leaderboard.");
+    }
+    if !digest.level_ups.is_empty() {
+        embed = embed.field(EmbedFieldBuilder::new(
+            "Level ups",
+            section(&digest.level_ups, |(user, level)| {
+                format!("{} reached level {level}", user.human_identifier())
+            }),
+        ));
+    }
+    if !digest.gainers.is_empty() {
+        embed = embed.field(EmbedFieldBuilder::new(
+            "Biggest gainers",
+            section(&digest.gainers, |(user, xp)| {
+                format!("{} gained {xp} XP", user.human_identifier())
+            }),
+        ));
+    }
+    if !digest.new_top.is_empty() {
+        embed = embed.field(EmbedFieldBuilder::new(
+            format!("New in the top {}", digest.top),
+            section(&digest.new_top, |user| {
+                format!("{} (#{})", user.human_identifier(), user.rank)
+            }),
+        ));
+    }
+    let embed = embed.build();
+    if notifier.dry_run {
+        let fields = embed.fields.iter().fold(String::new(), |mut out, field| {
+            let _ = write!(out, "\n{}:\n{}", field.name, field.value);
+            out
+        });
+        info!(
+            "Dry run, not sending the {} to webhook {}:{fields}",
+            digest.description().to_ascii_lowercase(),
+            webhook.marker
+        );
+        return Ok(());
+    }
+    let image = Attachment {
+        description: None,
+        file: leaderboard,
+        filename: "leaderboard.png".to_string(),
+        id: 0,
+    };
+    let avatar_url = notifier.avatar_url(&state.root_url);
+    let mut hook_builder = webhook
+        .client
+        .execute_webhook(webhook.marker, &webhook.token)
+        .username(notifier.username())?
+        .avatar_url(&avatar_url);
+    if let Some(thread_id) = webhook.thread {
+        hook_builder = hook_builder.thread_id(thread_id);
+    }
+    hook_builder
+        .attachments(&[image])?
+        .embeds(&[embed])?
+        .await?;
+    Ok(())
+}
+
+/// One line per item, up to [`SECTION_LENGTH`] of them, and as many as fit in an embed field
+fn section<T>(items: &[T], line: impl Fn(&T) -> String) -> String {
+    let mut out = String::new();
+    let mut shown = 0;
+    for item in items.iter().take(SECTION_LENGTH) {
+        let line = format!("{}\n", line(item));
+        // Leave room to mention whatever doesn't get shown
+        let rest = items.len() - shown - 1;
+        let tail = if rest > 0 {
+            format!("and {rest} more").len()
+        } else {
+            0
+        };
+        if out.chars().count() + line.chars().count() + tail > FIELD_LENGTH {
+            break;
+        }
+        out.push_str(&line);
+        shown += 1;
+    }
+    if shown < items.len() {
+        let _ = write!(out, "and {} more", items.len() - shown);
+    }
+    out
+}
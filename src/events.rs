@@ -0,0 +1,117 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Query, State},
+    response::sse::{self, KeepAlive, Sse},
+};
+use deadpool_redis::Connection;
+use futures_util::{Stream, StreamExt};
+use tokio::sync::broadcast;
+
+use crate::{hooks::HookEvent, AppState, Error};
+
+/// Redis pub/sub channel every replica's sync publishes its events to
+const CHANNEL: &str = "search6:events";
+/// How many events a slow `/events` client can fall behind before it starts missing some
+const BUFFER: usize = 1024;
+
+/// Events from every replica's sync, fanned out to this replica's `/events` clients
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Arc<BusEvent>>,
+}
+
+pub struct BusEvent {
+    kind: String,
+    user_id: Option<u64>,
+    json: String,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(BUFFER);
+        Self { sender }
+    }
+}
+
+pub async fn publish(redis: &mut Connection, events: &[HookEvent]) -> Result<(), Error> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    let mut pipe = redis::pipe();
+    for event in events {
+        pipe.publish(CHANNEL, serde_json::to_string(event)?)
+            .ignore();
+    }
+    pipe.query_async::<_, ()>(redis).await?;
+    Ok(())
+}
+
+/// Relays the Redis channel onto the bus, reconnecting whenever the subscription drops
+pub async fn subscribe_loop(redis_url: String, bus: EventBus) {
+    loop {
+        if let Err(e) = subscribe(&redis_url, &bus).await {
+            error!("Event subscription failed: {e:?}");
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn subscribe(redis_url: &str, bus: &EventBus) -> Result<(), Error> {
+    let mut pubsub = redis::Client::open(redis_url)?
+        .get_async_connection()
+        .await?
+        .into_pubsub();
+    pubsub.subscribe(CHANNEL).await?;
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let json: String = message.get_payload()?;
+        let event: HookEvent = match serde_json::from_str(&json) {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Ignoring malformed published event: {e:?}");
+                continue;
+            }
+        };
+        // No receivers just means nobody is connected to /events right now
+        let _ = bus.sender.send(Arc::new(BusEvent {
+            kind: event.kind().to_string(),
+            user_id: event.user_id(),
+            json,
+        }));
+    }
+    Ok(())
+}
+
+pub async fn stream(
+    State(state): State<AppState>,
+    Query(query): Query<EventsQuery>,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let receiver = state.events.sender.subscribe();
+    let events = futures_util::stream::unfold(receiver, move |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    // Per-user filters still get sync pass completions, which aren't about anyone
+                    if query.user.is_some()
+                        && event.user_id.is_some_and(|id| Some(id) != query.user)
+                    {
+                        continue;
+                    }
+                    let sse = sse::Event::default().event(&event.kind).data(&event.json);
+                    return Some((Ok(sse), receiver));
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    debug!("/events client fell behind and missed {missed} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[derive(serde::Deserialize)]
+pub struct EventsQuery {
+    user: Option<u64>,
+}
@@ -0,0 +1,122 @@
+use std::collections::HashSet;
+
+use axum::extract::State;
+use chrono::{TimeZone, Utc};
+use deadpool_redis::Connection;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::{account::OPTOUT_KEY, admin::HIDDEN_KEY, notify::Event, AppState, Error, User};
+
+/// List of [`FeedEntry`]s, newest first
+pub const FEED_KEY: &str = "feed:milestones";
+pub const FEED_LENGTH: usize = 50;
+
+// Removes every entry in the feed KEYS[1] containing ARGV[1]
+const FORGET_SCRIPT: &str = r"
+for _, entry in ipairs(redis.call('LRANGE', KEYS[1], 0, -1)) do
+    if string.find(entry, ARGV[1], 1, true) then
+        redis.call('LREM', KEYS[1], 0, entry)
+    end
+end
+";
+
+#[derive(Serialize, Deserialize)]
+pub struct FeedEntry {
+    user: User,
+    level: u64,
+    /// When the sync noticed, in milliseconds since the epoch
+    at: i64,
+}
+
+/// The feed entry for an event, if it belongs in the feed
+pub fn entry(event: &Event, at: i64) -> Option<FeedEntry> {
+    match event {
+        Event::LevelUp { user, level } => Some(FeedEntry {
+            user: user.clone(),
+            level: *level,
+            at,
+        }),
+        Event::RankBracket { .. } | Event::Overtake { .. } => None,
+    }
+}
+
+/// The feed entries about `id`, newest first
+pub async fn entries_for(redis: &mut Connection, id: u64) -> Result<Vec<FeedEntry>, Error> {
+    let raw: Vec<String> = redis.lrange(FEED_KEY, 0, -1).await?;
+    Ok(raw
+        .iter()
+        .filter_map(|entry| serde_json::from_str::<FeedEntry>(entry).ok())
+        .filter(|entry| entry.user.id == id)
+        .collect())
+}
+
+/// Remove every feed entry about `id`
+pub async fn forget(redis: &mut Connection, id: u64) -> Result<(), Error> {
+    // The user's ID is the only "id" in an entry, and a username can't fake it since a quote
+    // right after a comma can't appear inside a JSON string
+    redis::cmd("EVAL")
+        .arg(FORGET_SCRIPT)
+        .arg(1)
+        .arg(FEED_KEY)
+        .arg(format!(",\"id\":{id},"))
+        .query_async::<_, ()>(redis)
+        .await?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct AtomEntry {
+    name: String,
+    level: u64,
+    xp: u64,
+    rank: i64,
+    page_url: String,
+    card_url: String,
+    updated: String,
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn atom(
+    State(state): State<AppState>,
+) -> Result<([(&'static str, &'static str); 2], String), Error> {
+    let mut redis = state.redis.get().await?;
+    let raw: Vec<String> = redis.lrange(FEED_KEY, 0, -1).await?;
+    let hidden: HashSet<u64> = redis.sunion(&[OPTOUT_KEY, HIDDEN_KEY]).await?;
+    let root_url = &*state.root_url;
+    let entries: Vec<AtomEntry> = raw
+        .iter()
+        .filter_map(|entry| serde_json::from_str::<FeedEntry>(entry).ok())
+        .filter(|entry| !hidden.contains(&entry.user.id))
+        .map(|entry| AtomEntry {
+            name: entry.user.human_identifier(),
+            level: entry.level,
+            xp: entry.user.xp,
+            rank: entry.user.rank,
+            page_url: format!("{root_url}/?id={}", entry.user.id),
+            card_url: format!("{root_url}/card?id={}", entry.user.id),
+            updated: rfc3339(entry.at),
+        })
+        .collect();
+    let updated = entries
+        .first()
+        .map_or_else(|| Utc::now().to_rfc3339(), |entry| entry.updated.clone());
+    let mut ctx = tera::Context::new();
+    ctx.insert("root_url", root_url);
+    ctx.insert("updated", &updated);
+    ctx.insert("entries", &entries);
+    Ok((
+        [
+            ("Content-Type", "application/atom+xml"),
+            ("Cache-Control", "public, max-age=300"),
+        ],
+        state.tera.render("feed.atom", &ctx)?,
+    ))
+}
+
+fn rfc3339(millis: i64) -> String {
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .unwrap_or_else(Utc::now)
+        .to_rfc3339()
+}
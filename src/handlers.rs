@@ -1,28 +1,55 @@
 use crate::{
+    account,
+    session::Session,
     util::{self, get_avatar_url, get_user},
     AppState, Error, User,
 };
 use axum::{
-    extract::{Json, Query, State},
+    extract::{Json, Path, Query, State},
     response::Html,
 };
 
 #[allow(clippy::missing_errors_doc)]
 pub async fn fetch_user(
     State(state): State<AppState>,
+    session: Option<Session>,
     Query(query): Query<SubmitQuery>,
+) -> Result<Html<String>, Error> {
+    render_user_page(&state, session, query.id, query.userexists).await
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn fetch_slug(
+    State(state): State<AppState>,
+    session: Option<Session>,
+    Path(slug): Path<String>,
+) -> Result<Html<String>, Error> {
+    render_user_page(&state, session, Some(slug), false).await
+}
+
+async fn render_user_page(
+    state: &AppState,
+    session: Option<Session>,
+    id: Option<String>,
+    user_exists: bool,
 ) -> Result<Html<String>, Error> {
     let mut ctx = tera::Context::new();
     ctx.insert("root_url", &*state.root_url);
-    let Some(id) = query.id else {
-        return Ok(Html(state.tera.render("index.html", &ctx)?))
+    ctx.insert("logged_in", &session.is_some());
+    let Some(id) = id else {
+        return Ok(Html(state.tera.render("index.html", &ctx)?));
     };
-    let user = get_user(&state, id, query.userexists).await?;
+    let user = get_user(state, id, user_exists).await?;
+    let mut redis = state.redis.get().await?;
+    ctx.insert("slug", &account::get_slug(&mut redis, user.id).await?);
     let level_info = mee6::LevelInfo::new(user.xp);
     ctx.insert("level", &level_info.level());
     ctx.insert("percentage", &level_info.percentage());
     ctx.insert("user", &user);
-    ctx.insert("avatar", &get_avatar_url(user.id, &user.avatar, true));
+    ctx.insert(
+        "avatar",
+        &get_avatar_url(user.id, user.avatar.as_deref(), true),
+    );
     if let Some(epoch_updated) = user.last_updated {
         if let Some(dur) = util::time_since_epoch(epoch_updated) {
             ctx.insert("user_last_update", &util::duration_fmt(dur));
@@ -42,7 +69,7 @@ pub async fn fetch_card(
     let ctx = util::get_user_context(&state, id, query.userexists).await?;
     Ok((
         [("Content-Type", "image/png")],
-        state.svg.render(ctx).await?,
+        state.render_queue.render_card(&state.svg, ctx).await?,
     ))
 }
 
@@ -76,14 +103,14 @@ pub async fn fetch_json(
     Query(query): Query<SubmitQuery>,
 ) -> Result<([(&'static str, &'static str); 1], Json<ApiResponse>), Error> {
     let Some(id) = query.id else {
-        return Err(Error::NoId)
+        return Err(Error::NoId);
     };
     let user = get_user(&state, id, query.userexists).await?;
     let level_info = mee6::LevelInfo::new(user.xp);
     Ok((
         [("Access-Control-Allow-Origin", "*")],
         Json(ApiResponse {
-            avatar_url: get_avatar_url(user.id, &user.avatar, true),
+            avatar_url: get_avatar_url(user.id, user.avatar.as_deref(), true),
             level: level_info.level(),
             level_progress: level_info.percentage(),
             user,
@@ -91,11 +118,102 @@ pub async fn fetch_json(
     ))
 }
 
+#[allow(clippy::missing_errors_doc)]
+pub async fn fetch_badge(
+    State(state): State<AppState>,
+    Query(query): Query<BadgeQuery>,
+) -> Result<([(&'static str, &'static str); 2], String), Error> {
+    // Badges are embedded as images, so failures get a badge too, rather than the error page
+    let (label, value, value_color, cache_control) = match badge_value(&state, &query).await {
+        Ok((value, value_color)) => (
+            query.metric.label(),
+            value,
+            value_color,
+            "public, max-age=300",
+        ),
+        Err(e) => {
+            let value = match e {
+                Error::NoId | Error::UnknownId | Error::NotLevelFive => "not found",
+                Error::OptedOut | Error::Hidden => "hidden",
+                _ => "unavailable",
+            };
+            (
+                "user",
+                value.to_string(),
+                "#e05d44".to_string(),
+                "public, max-age=60",
+            )
+        }
+    };
+    let mut ctx = tera::Context::new();
+    ctx.insert("label", label);
+    ctx.insert("label_width", &badge_text_width(label));
+    ctx.insert("label_color", "#555");
+    ctx.insert("value_width", &badge_text_width(&value));
+    ctx.insert("value_color", &value_color);
+    ctx.insert("value", &value);
+    Ok((
+        [
+            ("Content-Type", "image/svg+xml"),
+            ("Cache-Control", cache_control),
+        ],
+        state.tera.render("badge.svg", &ctx)?,
+    ))
+}
+
+/// The badge's value, and its color
+async fn badge_value(state: &AppState, query: &BadgeQuery) -> Result<(String, String), Error> {
+    let Some(id) = query.id.clone() else {
+        return Err(Error::NoId);
+    };
+    let user = get_user(state, id, false).await?;
+    let colors = xpd_rank_card::colors::Colors::default();
+    Ok(match query.metric {
+        BadgeMetric::Level => (
+            mee6::LevelInfo::new(user.xp).level().to_string(),
+            colors.level.to_string(),
+        ),
+        BadgeMetric::Rank => (format!("#{}", user.rank), colors.border.to_string()),
+        BadgeMetric::Xp => (user.xp.to_string(), colors.progress_foreground.to_string()),
+    })
+}
+
+/// Rough width of 11px Verdana, plus padding, which is close enough for short badge text.
+fn badge_text_width(text: &str) -> usize {
+    text.chars().count() * 7 + 10
+}
+
 #[derive(serde::Deserialize)]
-pub struct SubmitQuery {
+pub struct BadgeQuery {
     id: Option<String>,
+    #[serde(default)]
+    metric: BadgeMetric,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BadgeMetric {
+    #[default]
+    Level,
+    Rank,
+    Xp,
+}
+
+impl BadgeMetric {
+    const fn label(self) -> &'static str {
+        match self {
+            Self::Level => "level",
+            Self::Rank => "rank",
+            Self::Xp => "xp",
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct SubmitQuery {
+    pub id: Option<String>,
     #[serde(default = "rfalse")]
-    userexists: bool,
+    pub userexists: bool,
 }
 
 const fn rfalse() -> bool {
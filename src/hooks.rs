@@ -0,0 +1,199 @@
+use std::{collections::HashMap, fmt::Write};
+
+use mee6::LevelInfo;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+use crate::{queue::Delivery, AppState, Error, User};
+
+/// Something that happened to a user, as sent to generic HTTP webhooks
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HookEvent {
+    LevelUp {
+        user: User,
+        old_level: u64,
+        new_level: u64,
+    },
+    RankChange {
+        user: User,
+        old_rank: i64,
+        new_rank: i64,
+    },
+    /// `user_id` was on the leaderboard last sync pass, but not this one. `user` is the last
+    /// we saw of them, if we still have it.
+    UserDeparted { user_id: u64, user: Option<User> },
+    /// The sync made it to the bottom of the leaderboard, after `pages` pages
+    SyncComplete { pages: i64 },
+}
+
+impl HookEvent {
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::LevelUp { .. } => "level_up",
+            Self::RankChange { .. } => "rank_change",
+            Self::UserDeparted { .. } => "user_departed",
+            Self::SyncComplete { .. } => "sync_complete",
+        }
+    }
+
+    pub const fn user_id(&self) -> Option<u64> {
+        match self {
+            Self::LevelUp { user, .. } | Self::RankChange { user, .. } => Some(user.id),
+            Self::UserDeparted { user_id, .. } => Some(*user_id),
+            Self::SyncComplete { .. } => None,
+        }
+    }
+
+    pub fn description(&self) -> String {
+        match self {
+            Self::LevelUp {
+                user, new_level, ..
+            } => format!("{} reached level {new_level}", user.human_identifier()),
+            Self::RankChange { user, new_rank, .. } => {
+                format!("{} moved to rank #{new_rank}", user.human_identifier())
+            }
+            Self::UserDeparted { user_id, .. } => format!("{user_id} left the leaderboard"),
+            Self::SyncComplete { pages } => format!("Sync pass finished after {pages} pages"),
+        }
+    }
+}
+
+/// An HTTP endpoint that gets [`HookEvent`]s, signed with a shared secret
+pub struct HttpSink {
+    pub url: String,
+    key: hmac::Key,
+    /// Event kinds to send, or every kind but the noisy `rank_change` when unset
+    events: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+pub struct SinkConfig {
+    url: String,
+    secret: String,
+    events: Option<Vec<String>>,
+}
+
+impl From<SinkConfig> for HttpSink {
+    fn from(config: SinkConfig) -> Self {
+        if let Some(events) = &config.events {
+            for event in events {
+                assert!(
+                    ["level_up", "rank_change", "user_departed", "sync_complete"]
+                        .contains(&event.as_str()),
+                    "Unknown HTTP webhook event kind {event}"
+                );
+            }
+        }
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, config.secret.as_bytes()),
+            url: config.url,
+            events: config.events,
+        }
+    }
+}
+
+impl HttpSink {
+    pub fn wants(&self, event: &HookEvent) -> bool {
+        self.events.as_ref().map_or_else(
+            || !matches!(event, HookEvent::RankChange { .. }),
+            |events| events.iter().any(|kind| kind == event.kind()),
+        )
+    }
+}
+
+/// One delivery per sink that wants each event
+pub fn deliveries(sinks: &[HttpSink], events: &[HookEvent]) -> Vec<Delivery> {
+    let mut deliveries = Vec::new();
+    for event in events {
+        for sink in sinks.iter().filter(|sink| sink.wants(event)) {
+            deliveries.push(Delivery::Http {
+                sink: sink.url.clone(),
+                event: event.clone(),
+            });
+        }
+    }
+    deliveries
+}
+
+/// Compare a page of users before and after a sync
+pub fn diff(old_users: &HashMap<u64, User>, new_users: &HashMap<u64, User>) -> Vec<HookEvent> {
+    let mut events = Vec::new();
+    for (id, new_user) in new_users {
+        let Some(old_user) = old_users.get(id) else {
+            continue;
+        };
+        let old_level = LevelInfo::new(old_user.xp).level();
+        let new_level = LevelInfo::new(new_user.xp).level();
+        if new_level > old_level {
+            events.push(HookEvent::LevelUp {
+                user: new_user.clone(),
+                old_level,
+                new_level,
+            });
+        }
+        // Only for whoever gained XP, not everyone they pushed down a place on their way up
+        if new_user.rank != old_user.rank && new_user.xp != old_user.xp {
+            events.push(HookEvent::RankChange {
+                user: new_user.clone(),
+                old_rank: old_user.rank,
+                new_rank: new_user.rank,
+            });
+        }
+    }
+    events
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    id: &'a str,
+    timestamp: i64,
+    event: &'a HookEvent,
+}
+
+/// POST `event` to the sink at `url`. The body is signed as `HMAC-SHA256(secret, "{timestamp}.{body}")`,
+/// sent hex-encoded in `X-Search6-Signature` alongside the `X-Search6-Timestamp` it covers.
+pub async fn deliver(
+    state: &AppState,
+    url: &str,
+    id: &str,
+    event: &HookEvent,
+) -> Result<(), Error> {
+    let Some(sink) = state.notifier.sinks.iter().find(|sink| sink.url == url) else {
+        warn!("Dropping webhook {id} for {url}, which is no longer configured");
+        return Ok(());
+    };
+    let timestamp = chrono::Utc::now().timestamp();
+    let body = serde_json::to_string(&Payload {
+        id,
+        timestamp,
+        event,
+    })?;
+    if state.notifier.dry_run {
+        info!("Dry run, not sending webhook {id} to {url}: {body}");
+        return Ok(());
+    }
+    let signature = sign(&sink.key, timestamp, &body);
+    state
+        .http
+        .post(&sink.url)
+        .header("Content-Type", "application/json")
+        .header("X-Search6-Delivery", id)
+        .header("X-Search6-Timestamp", timestamp.to_string())
+        .header("X-Search6-Signature", format!("sha256={signature}"))
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+fn sign(key: &hmac::Key, timestamp: i64, body: &str) -> String {
+    let tag = hmac::sign(key, format!("{timestamp}.{body}").as_bytes());
+    tag.as_ref()
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
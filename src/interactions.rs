@@ -0,0 +1,476 @@
+use std::{fmt::Write, sync::Arc};
+
+use axum::{body::Bytes, extract::State, http::HeaderMap, Json};
+use mee6::LevelInfo;
+use ring::signature::{self, UnparsedPublicKey};
+use twilight_model::{
+    application::interaction::{
+        application_command::CommandOptionValue, Interaction, InteractionData, InteractionType,
+    },
+    channel::message::{embed::Embed, MessageFlags},
+    http::{
+        attachment::Attachment,
+        interaction::{InteractionResponse, InteractionResponseData, InteractionResponseType},
+    },
+    id::{
+        marker::{ApplicationMarker, UserMarker},
+        Id,
+    },
+};
+use twilight_util::builder::embed::{EmbedBuilder, ImageSource};
+
+use crate::{leaderboard::get_leaderboard, util, AppState, Error, User};
+
+/// Rows in the `/leaderboard` image
+const LEADERBOARD_ROWS: i64 = 10;
+/// How many seconds an interaction's signature is good for
+const MAX_SIGNATURE_AGE: i64 = 300;
+
+#[derive(Clone)]
+pub struct InteractionsState {
+    /// The application's Ed25519 public key, from `DISCORD_PUBLIC_KEY`
+    pub key: Arc<UnparsedPublicKey<Vec<u8>>>,
+    /// Sends the replies to deferred commands
+    pub client: Arc<twilight_http::Client>,
+}
+
+pub fn get_interactions() -> Option<InteractionsState> {
+    let hex = std::env::var("DISCORD_PUBLIC_KEY").ok()?;
+    let key = decode_hex(hex.trim()).expect("Expected hex in DISCORD_PUBLIC_KEY");
+    Some(InteractionsState {
+        key: Arc::new(UnparsedPublicKey::new(&signature::ED25519, key)),
+        client: Arc::new(twilight_http::client::ClientBuilder::new().build()),
+    })
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Discord signs `{timestamp}{body}` with the application's key. `now` is in seconds since the
+/// epoch, and requests signed too long before or after it are rejected so they can't be replayed.
+fn verify(
+    key: &UnparsedPublicKey<Vec<u8>>,
+    headers: &HeaderMap,
+    body: &[u8],
+    now: i64,
+) -> Result<(), Error> {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or(Error::InvalidSignature)
+    };
+    let signature = decode_hex(header("X-Signature-Ed25519")?).ok_or(Error::InvalidSignature)?;
+    let timestamp = header("X-Signature-Timestamp")?;
+    let signed_at: i64 = timestamp.parse().map_err(|_| Error::InvalidSignature)?;
+    if (now - signed_at).abs() > MAX_SIGNATURE_AGE {
+        return Err(Error::InvalidSignature);
+    }
+    let mut message = timestamp.as_bytes().to_vec();
+    message.extend_from_slice(body);
+    key.verify(&message, &signature)
+        .map_err(|_| Error::InvalidSignature)
+}
+
+/// A slash command search6 knows how to answer
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    /// `/level` or `/rank`
+    User {
+        id: Id<UserMarker>,
+        rank: bool,
+    },
+    Leaderboard {
+        page: i64,
+    },
+}
+
+/// A command that has found what it's showing, and only has its image left to render
+enum Render {
+    Card(Embed, User),
+    Leaderboard(Embed, Vec<User>),
+}
+
+/// An embed showing off the image attached with it
+type Reply = (Embed, Attachment);
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn interactions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<InteractionResponse>, Error> {
+    let interactions = state
+        .interactions
+        .as_ref()
+        .ok_or(Error::InteractionsDisabled)?;
+    verify(
+        &interactions.key,
+        &headers,
+        &body,
+        chrono::Utc::now().timestamp(),
+    )?;
+    let interaction: Interaction = serde_json::from_slice(&body).map_err(|e| {
+        warn!("Failed to parse interaction: {e:?}");
+        Error::InvalidInteraction
+    })?;
+    let (response, command) = dispatch(&interaction);
+    let Some(command) = command else {
+        return Ok(Json(response));
+    };
+    let render = match command {
+        Command::User { id, rank } => user_command(&state, id, rank).await,
+        Command::Leaderboard { page } => leaderboard_command(&state, page).await,
+    };
+    // Errors are for the user that ran the command, which only works before deferring
+    let render = match render {
+        Ok(render) => render,
+        Err(e) => return Ok(Json(ephemeral(&e.to_string()))),
+    };
+    // Rendering can take longer than the 3 seconds Discord waits for a response
+    tokio::spawn(follow_up(
+        state.clone(),
+        interaction.application_id,
+        interaction.token,
+        render,
+    ));
+    Ok(Json(response))
+}
+
+/// What to answer Discord with right away, and the command to finish afterwards
+fn dispatch(interaction: &Interaction) -> (InteractionResponse, Option<Command>) {
+    let command = match (&interaction.kind, &interaction.data) {
+        (InteractionType::Ping, _) => {
+            let pong = InteractionResponse {
+                kind: InteractionResponseType::Pong,
+                data: None,
+            };
+            return (pong, None);
+        }
+        (InteractionType::ApplicationCommand, Some(InteractionData::ApplicationCommand(data))) => {
+            match data.name.as_str() {
+                "level" | "rank" => {
+                    // About whoever ran it if `user` is left out
+                    let id = data
+                        .options
+                        .iter()
+                        .find_map(|option| match option.value {
+                            CommandOptionValue::User(id) => Some(id),
+                            _ => None,
+                        })
+                        .or_else(|| interaction.author_id());
+                    let Some(id) = id else {
+                        return (ephemeral(&Error::NoId.to_string()), None);
+                    };
+                    Command::User {
+                        id,
+                        rank: data.name == "rank",
+                    }
+                }
+                "leaderboard" => Command::Leaderboard {
+                    page: data
+                        .options
+                        .iter()
+                        .find_map(|option| match option.value {
+                            CommandOptionValue::Integer(page) => Some(page.max(1)),
+                            _ => None,
+                        })
+                        .unwrap_or(1),
+                },
+                _ => return (ephemeral("search6 doesn't know that command"), None),
+            }
+        }
+        _ => return (ephemeral("search6 doesn't know how to handle that"), None),
+    };
+    let deferred = InteractionResponse {
+        kind: InteractionResponseType::DeferredChannelMessageWithSource,
+        data: None,
+    };
+    (deferred, Some(command))
+}
+
+/// Render a deferred command's image, and send it as a follow-up
+async fn follow_up(
+    state: AppState,
+    application_id: Id<ApplicationMarker>,
+    token: String,
+    render: Render,
+) {
+    let reply = match render {
+        Render::Card(embed, user) => render_card(&state, user).await.map(|card| {
+            (
+                embed,
+                Attachment::from_bytes("card.png".to_string(), card, 0),
+            )
+        }),
+        Render::Leaderboard(embed, users) => state
+            .render_queue
+            .render_leaderboard(&state.leaderboard, &state.root_url, &users)
+            .await
+            .map(|image| {
+                (
+                    embed,
+                    Attachment::from_bytes("leaderboard.png".to_string(), image, 0),
+                )
+            }),
+    };
+    if let Err(e) = send_follow_up(&state, application_id, &token, reply).await {
+        error!("Failed to answer interaction: {e:?}");
+    }
+}
+
+async fn send_follow_up(
+    state: &AppState,
+    application_id: Id<ApplicationMarker>,
+    token: &str,
+    reply: Result<Reply, Error>,
+) -> Result<(), Error> {
+    let interactions = state
+        .interactions
+        .as_ref()
+        .ok_or(Error::InteractionsDisabled)?;
+    let client = interactions.client.interaction(application_id);
+    let follow_up = client.create_followup(token);
+    match reply {
+        Ok((embed, attachment)) => {
+            follow_up
+                .embeds(&[embed])?
+                .attachments(&[attachment])?
+                .await?;
+        }
+        // The deferred reply was public, and so is whatever follows it
+        Err(e) => {
+            follow_up.content(&e.to_string())?.await?;
+        }
+    }
+    Ok(())
+}
+
+/// `/level [user]` and `/rank [user]`
+async fn user_command(state: &AppState, id: Id<UserMarker>, rank: bool) -> Result<Render, Error> {
+    let user = util::get_user(state, id.to_string(), true).await?;
+    let level_info = LevelInfo::new(user.xp);
+    let description = if rank {
+        let mut description = format!("Rank #{} with {} XP", user.rank, user.xp);
+        if let Some(ahead) = user_ahead(state, user.rank).await? {
+            let _ = write!(
+                description,
+                "\n{} XP behind {} (#{})",
+                ahead.xp.saturating_sub(user.xp),
+                ahead.human_identifier(),
+                ahead.rank
+            );
+        }
+        description
+    } else {
+        format!(
+            "Level {} ({:.0}% of the way to {}), rank #{}, {} XP",
+            level_info.level(),
+            level_info.percentage() * 100.0,
+            level_info.level() + 1,
+            user.rank,
+            user.xp
+        )
+    };
+    let embed = EmbedBuilder::new()
+        .title(user.human_identifier())
+        .url(format!("{}/?id={}", state.root_url, user.id))
+        .description(description)
+        .image(ImageSource::attachment("card.png")?)
+        .build();
+    Ok(Render::Card(embed, user))
+}
+
+async fn render_card(state: &AppState, user: User) -> Result<Vec<u8>, Error> {
+    let card_svg = util::user_context(state, user).await?;
+    state.render_queue.render_card(&state.svg, card_svg).await
+}
+
+/// `/leaderboard [page]`
+async fn leaderboard_command(state: &AppState, page: i64) -> Result<Render, Error> {
+    let start = (page - 1) * LEADERBOARD_ROWS + 1;
+    let users = get_leaderboard(state, start, LEADERBOARD_ROWS).await?;
+    if users.is_empty() {
+        return Err(Error::EmptyLeaderboardPage);
+    }
+    let mut description = String::new();
+    for user in &users {
+        let _ = writeln!(
+            description,
+            "#{} {} - level {}",
+            user.rank,
+            user.human_identifier(),
+            LevelInfo::new(user.xp).level()
+        );
+    }
+    let embed = EmbedBuilder::new()
+        .title(format!("Leaderboard, page {page}"))
+        .url(format!("{}/leaderboard.png?start={start}", state.root_url))
+        .description(description)
+        .image(ImageSource::attachment("leaderboard.png")?)
+        .build();
+    Ok(Render::Leaderboard(embed, users))
+}
+
+/// The nearest user ranked above `rank`, looking past the gaps hidden users leave
+async fn user_ahead(state: &AppState, rank: i64) -> Result<Option<User>, Error> {
+    if rank <= 1 {
+        return Ok(None);
+    }
+    let start = (rank - LEADERBOARD_ROWS).max(1);
+    Ok(get_leaderboard(state, start, rank - start).await?.pop())
+}
+
+/// A message only the user that ran the command can see
+fn ephemeral(content: &str) -> InteractionResponse {
+    InteractionResponse {
+        kind: InteractionResponseType::ChannelMessageWithSource,
+        data: Some(InteractionResponseData {
+            content: Some(content.to_string()),
+            flags: Some(MessageFlags::EPHEMERAL),
+            ..Default::default()
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Public half of the key the fixtures in `resources/interactions` were signed with
+    const TEST_KEY: &str = "d20e20b46abaeb9171421915c692cc874238a6516ef535ca7bc4b23fa2b83d5f";
+    /// When the fixtures were signed
+    const SIGNED_AT: i64 = 1_700_000_000;
+
+    const PING: (&str, &str) = (
+        include_str!("resources/interactions/ping.json"),
+        include_str!("resources/interactions/ping.sig"),
+    );
+    const LEVEL: (&str, &str) = (
+        include_str!("resources/interactions/level.json"),
+        include_str!("resources/interactions/level.sig"),
+    );
+    const RANK: (&str, &str) = (
+        include_str!("resources/interactions/rank.json"),
+        include_str!("resources/interactions/rank.sig"),
+    );
+    const LEADERBOARD: (&str, &str) = (
+        include_str!("resources/interactions/leaderboard.json"),
+        include_str!("resources/interactions/leaderboard.sig"),
+    );
+
+    fn key() -> UnparsedPublicKey<Vec<u8>> {
+        UnparsedPublicKey::new(&signature::ED25519, decode_hex(TEST_KEY).unwrap())
+    }
+
+    fn headers(signature: &str, timestamp: i64) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Signature-Ed25519", signature.parse().unwrap());
+        headers.insert("X-Signature-Timestamp", timestamp.into());
+        headers
+    }
+
+    fn dispatch_fixture((body, signature): (&str, &str)) -> (InteractionResponse, Option<Command>) {
+        verify(
+            &key(),
+            &headers(signature, SIGNED_AT),
+            body.as_bytes(),
+            SIGNED_AT,
+        )
+        .unwrap();
+        dispatch(&serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn accepts_good_signatures() {
+        for (body, signature) in [PING, LEVEL, RANK, LEADERBOARD] {
+            let headers = headers(signature, SIGNED_AT);
+            assert!(verify(&key(), &headers, body.as_bytes(), SIGNED_AT + 60).is_ok());
+        }
+    }
+
+    #[test]
+    fn rejects_bad_signatures() {
+        let (body, signature) = PING;
+        let rejects = |headers: HeaderMap, body: &str| {
+            verify(&key(), &headers, body.as_bytes(), SIGNED_AT).is_err()
+        };
+        let tampered = body.replace("\"token\":\"t\"", "\"token\":\"u\"");
+        assert!(rejects(headers(signature, SIGNED_AT), &tampered));
+        // Signed by the right key, but for a different body
+        assert!(rejects(headers(LEVEL.1, SIGNED_AT), body));
+        // The timestamp is part of what's signed
+        assert!(rejects(headers(signature, SIGNED_AT + 1), body));
+        assert!(rejects(headers("not hex", SIGNED_AT), body));
+        assert!(rejects(HeaderMap::new(), body));
+    }
+
+    #[test]
+    fn rejects_stale_timestamps() {
+        let (body, signature) = PING;
+        let headers = headers(signature, SIGNED_AT);
+        let stale = SIGNED_AT + MAX_SIGNATURE_AGE + 1;
+        assert!(verify(&key(), &headers, body.as_bytes(), stale).is_err());
+        let early = SIGNED_AT - MAX_SIGNATURE_AGE - 1;
+        assert!(verify(&key(), &headers, body.as_bytes(), early).is_err());
+    }
+
+    #[test]
+    fn answers_ping_with_pong() {
+        let (response, command) = dispatch_fixture(PING);
+        assert_eq!(response.kind, InteractionResponseType::Pong);
+        assert!(response.data.is_none());
+        assert!(command.is_none());
+    }
+
+    #[test]
+    fn defers_commands() {
+        let deferred = InteractionResponseType::DeferredChannelMessageWithSource;
+        let (response, command) = dispatch_fixture(LEVEL);
+        assert_eq!(response.kind, deferred);
+        assert_eq!(
+            command,
+            Some(Command::User {
+                id: Id::new(7),
+                rank: false
+            })
+        );
+        // Without a user, it's about whoever ran it
+        let (response, command) = dispatch_fixture(RANK);
+        assert_eq!(response.kind, deferred);
+        assert_eq!(
+            command,
+            Some(Command::User {
+                id: Id::new(5),
+                rank: true
+            })
+        );
+        let (response, command) = dispatch_fixture(LEADERBOARD);
+        assert_eq!(response.kind, deferred);
+        assert_eq!(command, Some(Command::Leaderboard { page: 3 }));
+    }
+
+    #[test]
+    fn rejects_unknown_commands() {
+        let (body, _) = LEVEL;
+        let unknown: Interaction =
+            serde_json::from_str(&body.replace("\"level\"", "\"nope\"")).unwrap();
+        let (response, command) = dispatch(&unknown);
+        assert_eq!(
+            response.kind,
+            InteractionResponseType::ChannelMessageWithSource
+        );
+        assert_eq!(
+            response.data.and_then(|data| data.flags),
+            Some(MessageFlags::EPHEMERAL)
+        );
+        assert!(command.is_none());
+    }
+}
@@ -0,0 +1,172 @@
+use std::sync::Arc;
+
+use axum::extract::{Query, State};
+use redis::AsyncCommands;
+use resvg::usvg::{fontdb, TreeParsing, TreeTextToPath};
+use xpd_rank_card::{colors::Colors, Font};
+
+use crate::{AppState, Error, User};
+
+const MAX_ROWS: i64 = 25;
+
+#[derive(Clone)]
+pub struct LeaderboardState {
+    fonts: Arc<fontdb::Database>,
+    tera: Arc<tera::Tera>,
+}
+
+impl LeaderboardState {
+    #[must_use]
+    pub fn new() -> Self {
+        let mut fonts = fontdb::Database::new();
+        fonts.load_font_data(Font::Mojang.ttf().to_vec());
+        let mut tera = tera::Tera::default();
+        tera.autoescape_on(vec!["svg"]);
+        tera.add_raw_template("svg", include_str!("resources/leaderboard.svg"))
+            .expect("Failed to build leaderboard.svg template!");
+        Self {
+            fonts: Arc::new(fonts),
+            tera: Arc::new(tera),
+        }
+    }
+
+    /// Renders the leaderboard SVG for the given users. This is fast and does not need to be async.
+    pub fn render_svg(&self, root_url: &str, users: &[User]) -> Result<String, Error> {
+        let rows: Vec<Row> = users.iter().map(Row::from).collect();
+        let title = match (rows.first(), rows.last()) {
+            (Some(first), Some(last)) => format!("Leaderboard #{} - #{}", first.rank, last.rank),
+            _ => "Leaderboard".to_string(),
+        };
+        let mut ctx = tera::Context::new();
+        ctx.insert("height", &(rows.len() * 60 + 100));
+        ctx.insert("rows", &rows);
+        ctx.insert("title", &title);
+        ctx.insert("root_url", root_url);
+        ctx.insert("font", &Font::Mojang);
+        ctx.insert("colors", &Colors::default());
+        Ok(self.tera.render("svg", &ctx)?)
+    }
+
+    /// Renders the leaderboard on the blocking thread pool and returns PNG-encoded image data.
+    pub async fn render(&self, root_url: &str, users: &[User]) -> Result<Vec<u8>, Error> {
+        let svg = self.render_svg(root_url, users)?;
+        let fonts = self.fonts.clone();
+        tokio::task::spawn_blocking(move || rasterize(&svg, &fonts)).await?
+    }
+}
+
+fn rasterize(svg: &str, fonts: &fontdb::Database) -> Result<Vec<u8>, Error> {
+    let opt = resvg::usvg::Options {
+        font_family: Font::Mojang.to_string(),
+        ..Default::default()
+    };
+    let mut tree = resvg::usvg::Tree::from_str(svg, &opt)?;
+    tree.convert_text(fonts);
+    let size = tree.size.to_int_size();
+    let mut pixmap =
+        resvg::tiny_skia::Pixmap::new(size.width(), size.height()).ok_or(Error::PixmapCreation)?;
+    resvg::Tree::from_usvg(&tree)
+        .render(resvg::tiny_skia::Transform::default(), &mut pixmap.as_mut());
+    Ok(pixmap.encode_png()?)
+}
+
+#[derive(serde::Serialize)]
+struct Row {
+    rank: i64,
+    name: String,
+    discriminator: Option<String>,
+    level: u64,
+    xp: u64,
+    percentage: u64,
+}
+
+impl From<&User> for Row {
+    fn from(user: &User) -> Self {
+        let level_info = mee6::LevelInfo::new(user.xp);
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        Self {
+            rank: user.rank,
+            name: user.username.clone(),
+            discriminator: user.discriminator.clone(),
+            level: level_info.level(),
+            xp: user.xp,
+            percentage: (level_info.percentage() * 100.0).round() as u64,
+        }
+    }
+}
+
+/// Fetches the users ranked from `start` to `start + count`, in rank order. Ranks held by hidden
+/// users are left out, so there may be fewer than `count`.
+pub async fn get_leaderboard(state: &AppState, start: i64, count: i64) -> Result<Vec<User>, Error> {
+    let start = start.max(1);
+    let count = count.clamp(1, MAX_ROWS);
+    let mut redis = state.redis.get().await?;
+    let ranks: Vec<i64> = (start..start.saturating_add(count)).collect();
+    let rank_keys: Vec<String> = ranks
+        .iter()
+        .map(|rank| format!("user.rank:{rank}"))
+        .collect();
+    let ids: Vec<Option<String>> = redis.mget(rank_keys).await?;
+    let (ranks, user_keys): (Vec<i64>, Vec<String>) = ranks
+        .into_iter()
+        .zip(ids)
+        .filter_map(|(rank, id)| Some((rank, format!("user.id:{}", id?))))
+        .unzip();
+    if user_keys.is_empty() {
+        return Ok(Vec::new());
+    }
+    let users: Vec<Option<String>> = redis.mget(user_keys).await?;
+    let mut out = Vec::with_capacity(users.len());
+    for (rank, user) in ranks.into_iter().zip(users) {
+        let Some(user) = user else { continue };
+        let user: User = serde_json::from_str(&user)?;
+        // Someone who moved still holds their old rank until the sync gets to it
+        if user.rank == rank {
+            out.push(user);
+        }
+    }
+    Ok(out)
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn leaderboard_png(
+    State(state): State<AppState>,
+    Query(query): Query<LeaderboardQuery>,
+) -> Result<([(&'static str, &'static str); 1], Vec<u8>), Error> {
+    let users = get_leaderboard(&state, query.start, query.count).await?;
+    Ok((
+        [("Content-Type", "image/png")],
+        state
+            .render_queue
+            .render_leaderboard(&state.leaderboard, &state.root_url, &users)
+            .await?,
+    ))
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn leaderboard_svg(
+    State(state): State<AppState>,
+    Query(query): Query<LeaderboardQuery>,
+) -> Result<([(&'static str, &'static str); 1], String), Error> {
+    let users = get_leaderboard(&state, query.start, query.count).await?;
+    Ok((
+        [("Content-Type", "image/svg+xml")],
+        state.leaderboard.render_svg(&state.root_url, &users)?,
+    ))
+}
+
+#[derive(serde::Deserialize)]
+pub struct LeaderboardQuery {
+    #[serde(default = "default_start")]
+    start: i64,
+    #[serde(default = "default_count")]
+    count: i64,
+}
+
+const fn default_start() -> i64 {
+    1
+}
+
+const fn default_count() -> i64 {
+    10
+}
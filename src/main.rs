@@ -1,11 +1,26 @@
 #![warn(clippy::all, clippy::nursery, clippy::pedantic)]
+mod account;
+mod admin;
+mod animated;
+mod digest;
+mod events;
+mod feed;
 mod handlers;
+mod hooks;
+mod interactions;
+mod leaderboard;
+mod notify;
 mod oauth;
+mod queue;
 mod reload;
+mod render;
+mod session;
+mod subscription;
 mod util;
 use axum::{
+    http::StatusCode,
     response::{Html, IntoResponse},
-    routing::get,
+    routing::{get, post},
 };
 use deadpool_redis::{Config, Runtime};
 use serde::Deserialize;
@@ -38,57 +53,133 @@ async fn main() {
         .expect("Expected valid server ID in GUILD_ID");
     let redis_url = std::env::var("REDIS_URL").expect("Expected REDIS_URL in environment");
     let oauth = util::get_oauth(&root_url);
+    let guild_check = util::get_guild_check();
     let webhook = util::get_webhook();
-    if webhook.is_none() {
-        warn!("webhook functionality disabled! (if you aren't valk, you can ignore this)");
-    } else {
+    let notifier = notify::Notifier::load(webhook.as_ref());
+    if notifier.enabled(webhook.as_ref()) {
         info!("Webhook level-up notifications enabled!");
+    } else {
+        warn!("webhook functionality disabled! (if you aren't valk, you can ignore this)");
+    }
+    let interactions = interactions::get_interactions();
+    if interactions.is_some() {
+        info!("Discord interactions enabled!");
     }
     if oauth.is_none() {
         warn!("OAuth2 functionality disabled! (if you aren't valk, you can ignore this)");
     } else {
         info!("OAuth2 enabled!");
     }
+    if let Some(check) = guild_check {
+        info!(
+            "Verifying guild membership with the {} scope",
+            check.scope()
+        );
+    }
     let http = reqwest::Client::new();
     let mut tera = tera::Tera::default();
-    tera.add_raw_templates(vec![("index.html", include_str!("resources/index.html"))])
-        .unwrap();
+    tera.autoescape_on(vec![".html", ".htm", ".xml", ".svg", ".atom"]);
+    tera.add_raw_templates(vec![
+        ("index.html", include_str!("resources/index.html")),
+        ("account.html", include_str!("resources/account.html")),
+        ("admin.html", include_str!("resources/admin.html")),
+        ("badge.svg", include_str!("resources/badge.svg")),
+        ("feed.atom", include_str!("resources/feed.atom")),
+    ])
+    .unwrap();
     let pool_cfg = deadpool_redis::PoolConfig::new(25);
-    let mut redis_cfg = Config::from_url(redis_url);
+    let mut redis_cfg = Config::from_url(redis_url.clone());
     redis_cfg.pool = Some(pool_cfg);
     let redis = redis_cfg.create_pool(Some(Runtime::Tokio1)).unwrap();
     let state = AppState {
         tera: Arc::new(tera),
         oauth,
+        guild_check,
+        discord_api: Arc::new(util::get_discord_api()),
+        admins: admin::get_admins(),
         svg: SvgState::new(),
+        leaderboard: leaderboard::LeaderboardState::new(),
+        render_queue: render::RenderQueue::from_env(),
+        events: events::EventBus::new(),
+        delivery_queue: queue::DeliveryQueue::from_env(),
+        session_key: session::get_session_key(),
         http,
         redis,
         webhook,
+        interactions,
+        notifier: Arc::new(notifier),
         guild_id,
         root_url: Arc::new(root_url),
     };
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        let id = args.iter().skip(1).find(|arg| !arg.starts_with("--"));
+        let (Some(id), "test-webhook") = (id, args[0].as_str()) else {
+            eprintln!("Usage: search6 test-webhook <user> [--dry-run]");
+            std::process::exit(2);
+        };
+        let dry_run = args.iter().any(|arg| arg == "--dry-run");
+        if let Err(e) = admin::test_webhook_command(&state, id.clone(), dry_run).await {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
     tokio::spawn(reload::reload_loop(state.clone()));
-    let app = axum::Router::new()
+    tokio::spawn(queue::delivery_loop(state.clone()));
+    tokio::spawn(digest::digest_loop(state.clone()));
+    tokio::spawn(events::subscribe_loop(redis_url, state.events.clone()));
+    let app = router(state);
+    info!("Listening on http://localhost:8080/");
+    axum::Server::bind(&([0, 0, 0, 0], 8080).into())
+        .serve(app.into_make_service())
+        .await
+        .unwrap();
+}
+
+fn router(state: AppState) -> axum::Router {
+    axum::Router::new()
         .route("/", get(handlers::fetch_user))
         .route("/api", get(handlers::fetch_json))
+        .route("/api/me", get(account::member_stats))
         .route("/c", get(handlers::fetch_card))
         .route("/card", get(handlers::fetch_card))
         .route("/card.svg", get(handlers::fetch_svg))
+        .route("/card.gif", get(animated::fetch_animated_card))
+        .route("/badge.svg", get(handlers::fetch_badge))
+        .route("/leaderboard.png", get(leaderboard::leaderboard_png))
+        .route("/leaderboard.svg", get(leaderboard::leaderboard_svg))
         .route("/o", get(oauth::redirect))
         .route("/oc", get(oauth::set_id))
+        .route("/me", get(session::me))
+        .route("/logout", get(session::logout))
+        .route("/account", get(account::account))
+        .route("/account/privacy", post(account::set_privacy))
+        .route("/account/slug", post(account::set_slug))
+        .route("/account/subscribe", post(subscription::subscribe))
+        .route("/account/unsubscribe", post(subscription::unsubscribe))
+        .route("/account/export", get(account::export))
+        .route("/account/delete", post(account::delete))
+        .route("/u/:slug", get(handlers::fetch_slug))
+        .route("/admin", get(admin::dashboard))
+        .route("/admin/resync", post(admin::resync))
+        .route("/admin/hide", post(admin::set_hidden))
+        .route("/admin/purge", post(admin::purge))
+        .route("/admin/webhook", post(admin::test_webhook))
+        .route("/admin/queue/replay", post(admin::replay_notification))
+        .route("/admin/queue/discard", post(admin::discard_notification))
         .route("/style.css", get(handlers::style))
         .route("/mee6_bad.png", get(handlers::mee6bad))
         .route("/search6.png", get(handlers::logo))
         .route("/minecraft.woff", get(handlers::font))
-        .with_state(state);
-    info!("Listening on http://localhost:8080/");
-    axum::Server::bind(&([0, 0, 0, 0], 8080).into())
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+        .route("/events", get(events::stream))
+        .route("/feed.atom", get(feed::atom))
+        .route("/interactions", post(interactions::interactions))
+        .route("/metrics", get(render::metrics))
+        .with_state(state)
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
 pub struct User {
     pub xp: u64,
     pub id: u64,
@@ -150,10 +241,20 @@ pub struct Players {
 pub struct AppState {
     pub tera: Arc<tera::Tera>,
     pub oauth: Option<oauth2::basic::BasicClient>,
+    pub guild_check: Option<util::GuildCheck>,
+    pub discord_api: Arc<String>,
+    pub admins: Arc<std::collections::HashSet<u64>>,
     pub http: reqwest::Client,
     pub svg: SvgState,
+    pub leaderboard: leaderboard::LeaderboardState,
+    pub render_queue: render::RenderQueue,
+    pub events: events::EventBus,
+    pub delivery_queue: queue::DeliveryQueue,
+    pub session_key: ring::hmac::Key,
     pub redis: deadpool_redis::Pool,
     pub webhook: Option<util::WebhookState>,
+    pub interactions: Option<interactions::InteractionsState>,
+    pub notifier: Arc<notify::Notifier>,
     pub guild_id: Id<GuildMarker>,
     pub root_url: Arc<String>,
 }
@@ -166,6 +267,20 @@ pub enum Error {
     Reqwest(#[from] reqwest::Error),
     #[error("SVG error: {0:?}")]
     Svg(#[from] xpd_rank_card::Error),
+    #[error("uSVG error: {0:?}")]
+    Usvg(#[from] resvg::usvg::Error),
+    #[error("PNG encoding error: {0:?}")]
+    PngEncoding(#[from] png::EncodingError),
+    #[error("PNG decoding error: {0:?}")]
+    PngDecoding(#[from] png::DecodingError),
+    #[error("GIF decoding error: {0:?}")]
+    GifDecoding(#[from] gif::DecodingError),
+    #[error("GIF encoding error: {0:?}")]
+    GifEncoding(#[from] gif::EncodingError),
+    #[error("Pixmap creation error")]
+    PixmapCreation,
+    #[error("Render task error: {0:?}")]
+    Join(#[from] tokio::task::JoinError),
     #[error("Redis error: {0:?}")]
     Redis(#[from] deadpool_redis::redis::RedisError),
     #[error("Redis connection pool error: {0:?}")]
@@ -173,7 +288,7 @@ pub enum Error {
     #[error("JSON deserialization error: {0:?}")]
     Json(#[from] serde_json::Error),
     #[error("Twilight-HTTP error: {0:?}")]
-    Twilight(#[from] twilight_http::Error),
+    Twilight(Box<twilight_http::Error>),
     #[error("Twilight-Validate error: {0:?}")]
     TwilightValidate(#[from] twilight_validate::message::MessageValidationError),
     #[error("Twilight-ImageSource:URL error: {0:?}")]
@@ -188,20 +303,93 @@ pub enum Error {
     NoId,
     #[error("This user is not ranked or may be uncached")]
     NotLevelFive,
-    #[error("Invalid OAuth2 State")]
+    #[error("Your login attempt expired or was invalid, please try again")]
     InvalidState,
     #[error("OAuth2 Code Exchange failed")]
     CodeExchangeFailed,
+    #[error("You cancelled logging in with Discord")]
+    OauthDenied,
+    #[error("Discord could not log you in, please try again")]
+    OauthProvider,
+    #[error("The login callback was missing its code or state")]
+    OauthMalformedCallback,
+    #[error("This user's avatar is too large to animate")]
+    AvatarTooLarge,
     #[error("OAuth2 is disabled on this search6 instance")]
     OauthDisabled,
+    #[error("You must be logged in to do that")]
+    NotLoggedIn,
+    #[error("You must be a verified member of the server to do that")]
+    NotGuildMember,
+    #[error("Server membership verification is disabled on this search6 instance")]
+    GuildCheckDisabled,
+    #[error("This user has opted out of search6")]
+    OptedOut,
+    #[error("This user has been hidden by a search6 admin")]
+    Hidden,
+    #[error("You must be a search6 admin to do that")]
+    NotAdmin,
+    #[error("Webhook notifications are disabled on this search6 instance")]
+    WebhookDisabled,
+    #[error("You must confirm that you want your data deleted")]
+    DeletionNotConfirmed,
+    #[error("Invalid slug: {0}")]
+    InvalidSlug(&'static str),
+    #[error("That slug is already taken")]
+    SlugTaken,
+    #[error("Invalid alert: {0}")]
+    InvalidSubscription(&'static str),
+    #[error("search6 is rendering too many images right now, please try again shortly")]
+    RenderQueueFull(u64),
+    #[error("Discord interactions are disabled on this search6 instance")]
+    InteractionsDisabled,
+    #[error("Invalid interaction signature")]
+    InvalidSignature,
+    #[error("Malformed interaction")]
+    InvalidInteraction,
+    #[error("There's nobody on that page of the leaderboard")]
+    EmptyLeaderboardPage,
+}
+
+impl From<twilight_http::Error> for Error {
+    fn from(value: twilight_http::Error) -> Self {
+        Self::Twilight(Box::new(value))
+    }
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
         let mut context = tera::Context::new();
         context.insert("error", &self.to_string());
+        context.insert(
+            "retry_login",
+            &matches!(
+                self,
+                Self::InvalidState
+                    | Self::CodeExchangeFailed
+                    | Self::OauthDenied
+                    | Self::OauthProvider
+                    | Self::OauthMalformedCallback
+                    | Self::NotLoggedIn
+            ),
+        );
+        let (status, retry_after) = match self {
+            Self::RenderQueueFull(secs) => (StatusCode::SERVICE_UNAVAILABLE, Some(secs)),
+            Self::InvalidState | Self::OauthMalformedCallback | Self::InvalidInteraction => {
+                (StatusCode::BAD_REQUEST, None)
+            }
+            Self::OauthDenied => (StatusCode::FORBIDDEN, None),
+            Self::InvalidSignature => (StatusCode::UNAUTHORIZED, None),
+            Self::CodeExchangeFailed | Self::OauthProvider => (StatusCode::BAD_GATEWAY, None),
+            _ => (StatusCode::OK, None),
+        };
         match tera::Tera::one_off(include_str!("resources/error.html"), &context, true) {
-            Ok(v) => Html(v).into_response(),
+            Ok(v) => match retry_after {
+                Some(secs) => {
+                    (status, [("Retry-After", secs.to_string())], Html(v)).into_response()
+                }
+                None => (status, Html(v)).into_response(),
+            },
             Err(e) => format!(
                 "There was an error while processing your request.
                 Additionally, there was an error while trying to use
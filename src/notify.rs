@@ -0,0 +1,596 @@
+use std::{collections::HashMap, sync::Arc};
+
+use mee6::LevelInfo;
+use serde::{Deserialize, Serialize};
+use twilight_model::{
+    http::attachment::Attachment,
+    id::{marker::ChannelMarker, Id},
+};
+use twilight_util::builder::embed::{EmbedBuilder, EmbedFooterBuilder, ImageSource};
+
+use crate::{
+    digest::{self, DigestConfig},
+    hooks::{HttpSink, SinkConfig},
+    util::WebhookState,
+    AppState, Error, User,
+};
+
+const DEFAULT_MESSAGE: &str = "{{ card_url }} {{ mention }}";
+const DEFAULT_DESCRIPTION: &str = "{{ summary }}```{{ content }}```";
+const DEFAULT_USERNAME: &str = "search6 notifier";
+const DEFAULT_TEMPLATE: &str = "default";
+
+/// Something the sync noticed that might be worth announcing
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// `user` reached the milestone `level`
+    LevelUp { user: User, level: u64 },
+    /// `user` moved into the top `bracket`
+    RankBracket { user: User, bracket: i64 },
+    /// `user` took `rank` from `overtaken`, if we knew who held it
+    Overtake {
+        user: User,
+        rank: i64,
+        overtaken: Option<User>,
+    },
+}
+
+impl Event {
+    pub const fn user(&self) -> &User {
+        match self {
+            Self::LevelUp { user, .. }
+            | Self::RankBracket { user, .. }
+            | Self::Overtake { user, .. } => user,
+        }
+    }
+
+    pub fn into_user(self) -> User {
+        match self {
+            Self::LevelUp { user, .. }
+            | Self::RankBracket { user, .. }
+            | Self::Overtake { user, .. } => user,
+        }
+    }
+
+    /// The set and member that record this event having been announced, for events that
+    /// should only ever be announced once
+    pub fn announced_record(&self) -> Option<(String, String)> {
+        match self {
+            Self::LevelUp { user, level } => Some((announced_key(user.id), level.to_string())),
+            Self::RankBracket { .. } | Self::Overtake { .. } => None,
+        }
+    }
+
+    pub fn description(&self) -> String {
+        let user = self.user();
+        let who = format!("User {} (<@{}>)", user.human_identifier(), user.id);
+        match self {
+            Self::LevelUp { level, .. } => format!("{who} has reached level {level}"),
+            Self::RankBracket { bracket, .. } => format!("{who} has entered the top {bracket}"),
+            Self::Overtake {
+                rank,
+                overtaken: Some(overtaken),
+                ..
+            } => format!(
+                "{who} has overtaken {} (<@{}>) for rank #{rank}",
+                overtaken.human_identifier(),
+                overtaken.id
+            ),
+            Self::Overtake { rank, .. } => format!("{who} has taken rank #{rank}"),
+        }
+    }
+}
+
+/// Set of the milestone levels a user has already been announced for
+pub fn announced_key(id: u64) -> String {
+    format!("user.announced:{id}")
+}
+
+/// Which events get announced, and where
+pub struct Notifier {
+    pub milestones: Vec<Threshold>,
+    pub rank_brackets: Vec<Threshold>,
+    pub overtakes: Vec<Threshold>,
+    default_target: Target,
+    templates: tera::Tera,
+    username: String,
+    /// Defaults to this instance's `/mee6_bad.png`
+    avatar_url: Option<String>,
+    /// Defaults to this instance's `/search6.png`
+    thumbnail_url: Option<String>,
+    /// Generic HTTP webhooks
+    pub sinks: Vec<HttpSink>,
+    pub digest: Option<digest::Schedule>,
+    /// Log notifications instead of sending them
+    pub dry_run: bool,
+}
+
+/// A level or rank that triggers an announcement
+pub struct Threshold {
+    pub value: i64,
+    target: Target,
+}
+
+/// Where an event is announced, and the names of the templates it's announced with
+#[derive(Clone)]
+struct Target {
+    template: String,
+    has_title: bool,
+    has_footer: bool,
+    /// Falls back to `WEBHOOK` when unset
+    webhook: Option<WebhookState>,
+}
+
+/// A rendered announcement
+pub struct Message {
+    pub content: String,
+    pub title: Option<String>,
+    pub description: String,
+    pub footer: Option<String>,
+}
+
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Content: {}", self.content)?;
+        if let Some(title) = &self.title {
+            writeln!(f, "Title: {title}")?;
+        }
+        writeln!(f, "Description: {}", self.description)?;
+        if let Some(footer) = &self.footer {
+            writeln!(f, "Footer: {footer}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The JSON in `NOTIFY_CONFIG`, or the file it points to
+#[derive(Deserialize)]
+struct NotifyConfig {
+    #[serde(default = "default_milestones")]
+    milestones: Vec<MilestoneConfig>,
+    /// Announce users entering the top N
+    #[serde(default)]
+    rank_brackets: Vec<RankConfig>,
+    /// Announce users taking a specific rank from someone else
+    #[serde(default)]
+    overtakes: Vec<RankConfig>,
+    username: Option<String>,
+    avatar_url: Option<String>,
+    thumbnail_url: Option<String>,
+    /// Embed templates for every announcement that doesn't set its own
+    #[serde(default)]
+    embed: EmbedConfig,
+    #[serde(default)]
+    http_sinks: Vec<SinkConfig>,
+    /// Post a periodic summary instead of, or as well as, individual announcements
+    digest: Option<DigestConfig>,
+}
+
+#[derive(Deserialize)]
+struct MilestoneConfig {
+    level: u64,
+    #[serde(flatten)]
+    target: TargetConfig,
+}
+
+#[derive(Deserialize)]
+struct RankConfig {
+    rank: i64,
+    #[serde(flatten)]
+    target: TargetConfig,
+}
+
+#[derive(Deserialize, Default)]
+struct TargetConfig {
+    message: Option<String>,
+    webhook: Option<String>,
+    thread_id: Option<Id<ChannelMarker>>,
+    #[serde(default)]
+    embed: EmbedConfig,
+}
+
+#[derive(Deserialize, Default)]
+struct EmbedConfig {
+    title: Option<String>,
+    description: Option<String>,
+    footer: Option<String>,
+}
+
+fn default_milestones() -> Vec<MilestoneConfig> {
+    vec![MilestoneConfig {
+        level: 5,
+        target: TargetConfig::default(),
+    }]
+}
+
+impl NotifyConfig {
+    fn from_env() -> Self {
+        std::env::var("NOTIFY_CONFIG").map_or_else(
+            |_| Self {
+                milestones: default_milestones(),
+                rank_brackets: Vec::new(),
+                overtakes: Vec::new(),
+                username: None,
+                avatar_url: None,
+                thumbnail_url: None,
+                embed: EmbedConfig::default(),
+                http_sinks: Vec::new(),
+                digest: None,
+            },
+            |source| {
+                // Either the JSON itself, or a path to a file containing it
+                let json = if source.trim_start().starts_with('{') {
+                    source
+                } else {
+                    std::fs::read_to_string(&source).expect("Failed to read NOTIFY_CONFIG file")
+                };
+                serde_json::from_str(&json).expect("Invalid NOTIFY_CONFIG")
+            },
+        )
+    }
+}
+
+impl Notifier {
+    pub fn load(default_webhook: Option<&WebhookState>) -> Self {
+        let config = NotifyConfig::from_env();
+        let client = default_webhook.map_or_else(
+            || Arc::new(twilight_http::client::ClientBuilder::new().build()),
+            |webhook| webhook.client.clone(),
+        );
+        let mut templates = tera::Tera::default();
+        let global_embed = config.embed;
+        let mut add_templates = |name: &str, message: Option<&str>, embed: &EmbedConfig| {
+            let title = embed.title.as_deref().or(global_embed.title.as_deref());
+            let description = embed
+                .description
+                .as_deref()
+                .or(global_embed.description.as_deref())
+                .unwrap_or(DEFAULT_DESCRIPTION);
+            let footer = embed.footer.as_deref().or(global_embed.footer.as_deref());
+            let mut raw = vec![
+                (name.to_string(), message.unwrap_or(DEFAULT_MESSAGE)),
+                (format!("{name}.description"), description),
+            ];
+            if let Some(title) = title {
+                raw.push((format!("{name}.title"), title));
+            }
+            if let Some(footer) = footer {
+                raw.push((format!("{name}.footer"), footer));
+            }
+            templates
+                .add_raw_templates(raw)
+                .expect("Invalid notification template");
+            (title.is_some(), footer.is_some())
+        };
+        let (has_title, has_footer) =
+            add_templates(DEFAULT_TEMPLATE, None, &EmbedConfig::default());
+        let default_target = Target {
+            template: DEFAULT_TEMPLATE.to_string(),
+            has_title,
+            has_footer,
+            webhook: None,
+        };
+        let mut target = |name: String, config: TargetConfig| {
+            let (has_title, has_footer) =
+                add_templates(&name, config.message.as_deref(), &config.embed);
+            let webhook = config
+                .webhook
+                .map(|url| crate::util::parse_webhook(client.clone(), &url, config.thread_id));
+            if webhook.is_none() && default_webhook.is_none() {
+                warn!("Notification {name} has no webhook and WEBHOOK is unset, it will not be announced");
+            }
+            Target {
+                template: name,
+                has_title,
+                has_footer,
+                webhook,
+            }
+        };
+        #[allow(clippy::cast_possible_wrap)]
+        let milestones = config
+            .milestones
+            .into_iter()
+            .enumerate()
+            .map(|(i, m)| Threshold {
+                value: m.level as i64,
+                target: target(format!("milestone.{i}"), m.target),
+            })
+            .collect();
+        let rank_brackets = config
+            .rank_brackets
+            .into_iter()
+            .enumerate()
+            .map(|(i, r)| Threshold {
+                value: r.rank,
+                target: target(format!("rank_bracket.{i}"), r.target),
+            })
+            .collect();
+        let overtakes = config
+            .overtakes
+            .into_iter()
+            .enumerate()
+            .map(|(i, r)| Threshold {
+                value: r.rank,
+                target: target(format!("overtake.{i}"), r.target),
+            })
+            .collect();
+        Self {
+            milestones: sorted(milestones),
+            rank_brackets: sorted(rank_brackets),
+            overtakes: sorted(overtakes),
+            default_target,
+            templates,
+            username: config
+                .username
+                .unwrap_or_else(|| DEFAULT_USERNAME.to_string()),
+            avatar_url: config.avatar_url,
+            thumbnail_url: config.thumbnail_url,
+            sinks: config.http_sinks.into_iter().map(HttpSink::from).collect(),
+            digest: config
+                .digest
+                .map(|digest| digest::Schedule::new(digest, client)),
+            dry_run: std::env::var("NOTIFY_DRY_RUN")
+                .is_ok_and(|v| v.parse().expect("Expected true or false in NOTIFY_DRY_RUN")),
+        }
+    }
+
+    /// Whether any event has somewhere to be announced
+    pub fn enabled(&self, default_webhook: Option<&WebhookState>) -> bool {
+        default_webhook.is_some()
+            || self
+                .milestones
+                .iter()
+                .chain(&self.rank_brackets)
+                .chain(&self.overtakes)
+                .any(|t| t.target.webhook.is_some())
+    }
+
+    /// Whether events should be announced as they happen, rather than only in the digest
+    pub fn announces_individually(&self, default_webhook: Option<&WebhookState>) -> bool {
+        self.enabled(default_webhook)
+            && self
+                .digest
+                .as_ref()
+                .is_none_or(|digest| digest.announce_individually)
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn avatar_url(&self, root_url: &str) -> String {
+        self.avatar_url
+            .clone()
+            .unwrap_or_else(|| format!("{root_url}/mee6_bad.png"))
+    }
+
+    /// The highest milestone at or below `level`
+    pub fn reached(&self, level: u64) -> Option<u64> {
+        self.milestone_levels().rev().find(|m| *m <= level)
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    pub fn milestone_levels(&self) -> impl DoubleEndedIterator<Item = u64> + '_ {
+        self.milestones.iter().map(|m| m.value as u64)
+    }
+
+    /// Milestones a user is already past when we first see them, which should never be announced
+    pub fn already_reached(
+        &self,
+        old_users: &HashMap<u64, User>,
+        new_users: &HashMap<u64, User>,
+    ) -> Vec<(u64, Vec<u64>)> {
+        new_users
+            .values()
+            .filter(|user| !old_users.contains_key(&user.id))
+            .filter_map(|user| {
+                let level = LevelInfo::new(user.xp).level();
+                let reached: Vec<u64> = self.milestone_levels().filter(|m| *m <= level).collect();
+                (!reached.is_empty()).then_some((user.id, reached))
+            })
+            .collect()
+    }
+
+    /// Compare a page of users before and after a sync, returning everything worth announcing
+    pub fn diff(
+        &self,
+        old_users: &HashMap<u64, User>,
+        new_users: &HashMap<u64, User>,
+    ) -> Vec<Event> {
+        let old_by_rank: HashMap<i64, &User> =
+            old_users.values().map(|user| (user.rank, user)).collect();
+        let mut events = Vec::new();
+        for (id, new_user) in new_users {
+            let Some(old_user) = old_users.get(id) else {
+                continue;
+            };
+            let old_level = LevelInfo::new(old_user.xp).level();
+            let new_level = LevelInfo::new(new_user.xp).level();
+            for level in self
+                .milestone_levels()
+                .filter(|m| old_level < *m && *m <= new_level)
+            {
+                events.push(Event::LevelUp {
+                    user: new_user.clone(),
+                    level,
+                });
+            }
+            for bracket in self
+                .rank_brackets
+                .iter()
+                .filter(|b| old_user.rank > b.value && new_user.rank <= b.value)
+            {
+                events.push(Event::RankBracket {
+                    user: new_user.clone(),
+                    bracket: bracket.value,
+                });
+            }
+            if old_user.rank > new_user.rank
+                && self.overtakes.iter().any(|o| o.value == new_user.rank)
+            {
+                let overtaken = old_by_rank
+                    .get(&new_user.rank)
+                    .filter(|user| user.id != new_user.id)
+                    .map(|user| (*user).clone());
+                events.push(Event::Overtake {
+                    user: new_user.clone(),
+                    rank: new_user.rank,
+                    overtaken,
+                });
+            }
+        }
+        events
+    }
+
+    fn target(&self, event: &Event) -> &Target {
+        #[allow(clippy::cast_possible_wrap)]
+        let (thresholds, value) = match event {
+            Event::LevelUp { level, .. } => (&self.milestones, *level as i64),
+            Event::RankBracket { bracket, .. } => (&self.rank_brackets, *bracket),
+            Event::Overtake { rank, .. } => (&self.overtakes, *rank),
+        };
+        thresholds
+            .iter()
+            .find(|t| t.value == value)
+            .map_or(&self.default_target, |t| &t.target)
+    }
+
+    fn render(&self, state: &AppState, target: &Target, event: &Event) -> Result<Message, Error> {
+        let user = event.user();
+        let mut ctx = tera::Context::new();
+        ctx.insert("summary", &event.description());
+        ctx.insert("user", user);
+        ctx.insert("name", &user.human_identifier());
+        ctx.insert("level", &LevelInfo::new(user.xp).level());
+        ctx.insert("rank", &user.rank);
+        ctx.insert("xp", &user.xp);
+        ctx.insert("mention", &format!("<@{}>", user.id));
+        ctx.insert("root_url", &*state.root_url);
+        ctx.insert(
+            "card_url",
+            &format!("{}/card?id={}", &*state.root_url, user.id),
+        );
+        ctx.insert("page_url", &format!("{}/?id={}", &*state.root_url, user.id));
+        match event {
+            Event::LevelUp { level, .. } => ctx.insert("level", level),
+            Event::RankBracket { bracket, .. } => ctx.insert("bracket", bracket),
+            Event::Overtake { overtaken, .. } => {
+                ctx.insert("overtaken", overtaken);
+                ctx.insert(
+                    "overtaken_name",
+                    &overtaken.as_ref().map(User::human_identifier),
+                );
+                ctx.insert(
+                    "overtaken_mention",
+                    &overtaken.as_ref().map(|o| format!("<@{}>", o.id)),
+                );
+            }
+        }
+        let content = self.templates.render(&target.template, &ctx)?;
+        ctx.insert("content", &content);
+        let render_part = |part: &str| {
+            self.templates
+                .render(&format!("{}.{part}", target.template), &ctx)
+        };
+        Ok(Message {
+            title: target.has_title.then(|| render_part("title")).transpose()?,
+            description: render_part("description")?,
+            footer: target
+                .has_footer
+                .then(|| render_part("footer"))
+                .transpose()?,
+            content,
+        })
+    }
+}
+
+fn sorted(mut thresholds: Vec<Threshold>) -> Vec<Threshold> {
+    thresholds.sort_unstable_by_key(|t| t.value);
+    thresholds
+}
+
+pub async fn announce(state: &AppState, event: Event) -> Result<(), Error> {
+    deliver(state, event, state.notifier.dry_run).await?;
+    Ok(())
+}
+
+/// Announce the milestone `user` most recently reached, as a level-up would be. Everything but
+/// actually sending it happens when `dry_run` is set.
+pub async fn test_announcement(
+    state: &AppState,
+    user: User,
+    dry_run: bool,
+) -> Result<Message, Error> {
+    let level = LevelInfo::new(user.xp).level();
+    let level = state.notifier.reached(level).unwrap_or(level);
+    let dry_run = dry_run || state.notifier.dry_run;
+    deliver(state, Event::LevelUp { user, level }, dry_run).await
+}
+
+async fn deliver(state: &AppState, event: Event, dry_run: bool) -> Result<Message, Error> {
+    let target = state.notifier.target(&event);
+    let webhook = target
+        .webhook
+        .as_ref()
+        .or(state.webhook.as_ref())
+        .ok_or(Error::WebhookDisabled)?;
+    let message = state.notifier.render(state, target, &event)?;
+    send_hook(state, webhook, event.into_user(), &message, dry_run).await?;
+    Ok(message)
+}
+
+pub async fn send_hook(
+    state: &AppState,
+    webhook: &WebhookState,
+    user: User,
+    message: &Message,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let notifier = &state.notifier;
+    let thumbnail_url = notifier
+        .thumbnail_url
+        .clone()
+        .unwrap_or_else(|| format!("{}/search6.png", &*state.root_url));
+    let avatar_url = notifier.avatar_url(&state.root_url);
+    let mut embed = EmbedBuilder::new()
+        .image(ImageSource::attachment("card.png")?)
+        .thumbnail(ImageSource::url(thumbnail_url)?)
+        .description(&message.description);
+    if let Some(title) = &message.title {
+        embed = embed.title(title);
+    }
+    if let Some(footer) = &message.footer {
+        embed = embed.footer(EmbedFooterBuilder::new(footer));
+    }
+    let embed = embed.build();
+    let card_svg = crate::util::user_context(state, user).await?;
+    let card_raster = state.render_queue.render_card(&state.svg, card_svg).await?;
+    let card = Attachment {
+        description: None,
+        file: card_raster,
+        filename: "card.png".to_string(),
+        id: 0,
+    };
+    if dry_run {
+        info!(
+            "Dry run, not sending to webhook {} as {}:\n{message}",
+            webhook.marker, notifier.username
+        );
+        return Ok(());
+    }
+    let mut hook_builder = webhook
+        .client
+        .execute_webhook(webhook.marker, &webhook.token)
+        .username(notifier.username())?
+        .avatar_url(&avatar_url);
+    if let Some(thread_id) = webhook.thread {
+        hook_builder = hook_builder.thread_id(thread_id);
+    }
+    hook_builder
+        .content(&message.content)?
+        .attachments(&[card])?
+        .embeds(&[embed])?
+        .await?;
+    Ok(())
+}
@@ -1,26 +1,31 @@
 use axum::extract::{Query, State};
 use axum::response::Redirect;
+use oauth2::basic::BasicClient;
 use oauth2::reqwest::async_http_client;
 use oauth2::{
-    AuthorizationCode, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, Scope, TokenResponse,
+    AuthorizationCode, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, Scope,
+    StandardRevocableToken, TokenResponse,
 };
 use redis::AsyncCommands;
+use twilight_model::id::{marker::GuildMarker, Id};
 
-use crate::{AppState, Error};
+use crate::{util::GuildCheck, AppState, Error};
 
 pub async fn redirect(State(state): State<AppState>) -> Result<Redirect, Error> {
     let oauth = state.oauth.ok_or(Error::OauthDisabled)?;
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
-    let (auth_url, csrf_token) = oauth
+    let mut auth_request = oauth
         .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new("identify".to_string()))
-        .set_pkce_challenge(pkce_challenge)
-        .url();
+        .add_scope(Scope::new("identify".to_string()));
+    if let Some(check) = state.guild_check {
+        auth_request = auth_request.add_scope(Scope::new(check.scope().to_string()));
+    }
+    let (auth_url, csrf_token) = auth_request.set_pkce_challenge(pkce_challenge).url();
     state
         .redis
         .get()
         .await?
-        .set_ex(
+        .set_ex::<_, _, ()>(
             format!("csrf.token:{}", csrf_token.secret()),
             pkce_verifier.secret(),
             600,
@@ -32,45 +37,123 @@ pub async fn redirect(State(state): State<AppState>) -> Result<Redirect, Error>
 pub async fn set_id(
     State(state): State<AppState>,
     Query(query): Query<SetIdQuery>,
-) -> Result<Redirect, Error> {
-    let oauth = state.oauth.ok_or(Error::OauthDisabled)?;
-    let pkce_secret = state
+) -> Result<([(&'static str, String); 1], Redirect), Error> {
+    let oauth = state.oauth.clone().ok_or(Error::OauthDisabled)?;
+    if let Some(error) = query.error {
+        let description = query.error_description.unwrap_or_default();
+        if error == "access_denied" {
+            info!("User denied OAuth2 consent");
+            return Err(Error::OauthDenied);
+        }
+        // Anyone can link here with any error, so the description is only worth logging
+        warn!("OAuth2 provider returned error {error}: {description}");
+        return Err(Error::OauthProvider);
+    }
+    let (Some(code), Some(csrf_state)) = (query.code, query.state) else {
+        warn!("OAuth2 callback was missing code or state");
+        return Err(Error::OauthMalformedCallback);
+    };
+    let Some(pkce_secret) = state
         .redis
         .get()
         .await?
-        .get_del::<String, Option<String>>(format!("csrf.token:{}", query.state))
+        .get_del::<String, Option<String>>(format!("csrf.token:{csrf_state}"))
         .await?
-        .ok_or(Error::InvalidState)?;
+    else {
+        info!("OAuth2 callback had an unknown or expired CSRF state");
+        return Err(Error::InvalidState);
+    };
     let pkce_verifier = PkceCodeVerifier::new(pkce_secret);
     let token_result = oauth
-        .exchange_code(AuthorizationCode::new(query.code))
+        .exchange_code(AuthorizationCode::new(code))
         .set_pkce_verifier(pkce_verifier)
         .request_async(async_http_client)
         .await
-        .map_err(|_| Error::CodeExchangeFailed)?;
+        .map_err(|e| {
+            warn!("OAuth2 code exchange failed: {e:?}");
+            Error::CodeExchangeFailed
+        })?;
     let me: twilight_model::user::CurrentUser = state
         .http
-        .get("https://discord.com/api/v10/users/@me")
+        .get(format!("{}/users/@me", state.discord_api))
         .bearer_auth(token_result.access_token().secret())
         .send()
         .await?
         .json()
         .await?;
+    let member = match state.guild_check {
+        Some(check) => is_guild_member(&state, check, token_result.access_token().secret()).await?,
+        None => false,
+    };
     tokio::spawn(async move {
         if let Some(rt) = token_result.refresh_token() {
-            oauth.revoke_token(rt.into()).ok();
+            revoke(&oauth, rt.into()).await;
         }
-        oauth.revoke_token(token_result.access_token().into()).ok();
+        revoke(&oauth, token_result.access_token().into()).await;
     });
-    Ok(Redirect::to(&format!(
-        "/?id={}&userexists={}",
-        me.id.get(),
-        true
-    )))
+    let cookie = crate::session::create(&state, me.id.get(), member).await?;
+    Ok((
+        [("Set-Cookie", cookie)],
+        Redirect::to(&format!("/?id={}&userexists={}", me.id.get(), true)),
+    ))
+}
+
+async fn revoke(oauth: &BasicClient, token: StandardRevocableToken) {
+    let request = match oauth.revoke_token(token) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("Could not build token revocation request: {e:?}");
+            return;
+        }
+    };
+    if let Err(e) = request.request_async(async_http_client).await {
+        warn!("Token revocation failed: {e:?}");
+    }
+}
+
+async fn is_guild_member(state: &AppState, check: GuildCheck, token: &str) -> Result<bool, Error> {
+    match check {
+        GuildCheck::Guilds => {
+            let guilds: Vec<PartialGuild> = state
+                .http
+                .get(format!("{}/users/@me/guilds", state.discord_api))
+                .bearer_auth(token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            Ok(guilds.iter().any(|guild| guild.id == state.guild_id))
+        }
+        GuildCheck::Members => {
+            let resp = state
+                .http
+                .get(format!(
+                    "{}/users/@me/guilds/{}/member",
+                    state.discord_api, state.guild_id
+                ))
+                .bearer_auth(token)
+                .send()
+                .await?;
+            // Anything other than "unknown member" means we couldn't tell, so the login fails
+            if resp.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(false);
+            }
+            resp.error_for_status()?;
+            Ok(true)
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct PartialGuild {
+    id: Id<GuildMarker>,
 }
 
 #[derive(serde::Deserialize)]
 pub struct SetIdQuery {
-    code: String,
-    state: String,
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
 }
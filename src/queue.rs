@@ -0,0 +1,346 @@
+use std::time::Duration;
+
+use deadpool_redis::Connection;
+use rand::RngCore;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use twilight_http::{api_error::ApiError, error::ErrorType};
+
+use crate::{
+    digest::{self, Digest},
+    feed,
+    hooks::{self, HookEvent},
+    notify::Event,
+    subscription::{self, Subscription},
+    AppState, Error, User,
+};
+
+/// Sorted set of pending [`Job`]s, scored by when they are next due in milliseconds since the epoch
+pub const QUEUE_KEY: &str = "notify:queue";
+/// Hash of job ID to the [`Job`]s that ran out of attempts
+pub const DEAD_KEY: &str = "notify:dead";
+
+/// How long a worker may hold a job before it's assumed lost and handed out again
+const LEASE_MS: i64 = 120_000;
+const MAX_BACKOFF: Duration = Duration::from_hours(1);
+const BATCH_SIZE: usize = 10;
+
+// Hands out up to ARGV[3] due jobs, pushing their score out to the lease expiry so that no other
+// worker picks them up until then
+const CLAIM_SCRIPT: &str = r"
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[3])
+for _, job in ipairs(due) do
+    redis.call('ZADD', KEYS[1], ARGV[2], job)
+end
+return due
+";
+
+// Unless ARGV[1] is already in the announced set KEYS[2], queues the job ARGV[3] due at ARGV[2]
+// and adds ARGV[4] to the front of the feed KEYS[3], trimming it to ARGV[5] entries. Either may be
+// empty to skip it.
+const ANNOUNCE_ONCE_SCRIPT: &str = r"
+if redis.call('SADD', KEYS[2], ARGV[1]) == 1 then
+    if ARGV[3] ~= '' then
+        redis.call('ZADD', KEYS[1], ARGV[2], ARGV[3])
+    end
+    if ARGV[4] ~= '' then
+        redis.call('LPUSH', KEYS[3], ARGV[4])
+        redis.call('LTRIM', KEYS[3], 0, tonumber(ARGV[5]) - 1)
+    end
+end
+";
+
+/// Delivers announcements and webhooks from Redis, so they survive restarts and get retried when they fail
+#[derive(Clone, Copy)]
+pub struct DeliveryQueue {
+    max_attempts: u32,
+    retry_base: Duration,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    #[serde(flatten)]
+    pub delivery: Delivery,
+    pub attempts: u32,
+    pub created_at: i64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "delivery", rename_all = "snake_case")]
+pub enum Delivery {
+    /// An announcement through a Discord webhook
+    Discord { event: Event },
+    /// A signed POST to the generic webhook configured with this URL
+    Http { sink: String, event: HookEvent },
+    /// A scheduled summary through the digest's Discord webhook
+    Digest { digest: Digest },
+    /// A member's alert about `user`, through `WEBHOOK`
+    Subscription {
+        subscription: Subscription,
+        user: User,
+    },
+}
+
+impl Delivery {
+    pub fn description(&self) -> String {
+        match self {
+            Self::Discord { event } => event.description(),
+            Self::Http { sink, event } => format!("{} (to {sink})", event.description()),
+            Self::Digest { digest } => digest.description().to_string(),
+            Self::Subscription { subscription, user } => format!(
+                "Alert for {} about {}",
+                subscription.subscriber,
+                user.human_identifier()
+            ),
+        }
+    }
+}
+
+impl DeliveryQueue {
+    pub fn from_env() -> Self {
+        let max_attempts = std::env::var("NOTIFY_MAX_ATTEMPTS").map_or(5, |v| {
+            v.parse().expect("Expected a number in NOTIFY_MAX_ATTEMPTS")
+        });
+        let retry_base_ms = std::env::var("NOTIFY_RETRY_BASE_MS").map_or(30_000, |v| {
+            v.parse()
+                .expect("Expected a number in NOTIFY_RETRY_BASE_MS")
+        });
+        Self {
+            max_attempts,
+            retry_base: Duration::from_millis(retry_base_ms),
+        }
+    }
+
+    fn backoff(self, attempts: u32) -> Duration {
+        self.retry_base
+            .saturating_mul(2_u32.saturating_pow(attempts.saturating_sub(1)))
+            .min(MAX_BACKOFF)
+    }
+}
+
+pub async fn enqueue(redis: &mut Connection, deliveries: Vec<Delivery>) -> Result<(), Error> {
+    if deliveries.is_empty() {
+        return Ok(());
+    }
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut jobs = Vec::with_capacity(deliveries.len());
+    for delivery in deliveries {
+        jobs.push((now, new_job(delivery, now)?));
+    }
+    redis.zadd_multiple::<_, _, _, ()>(QUEUE_KEY, &jobs).await?;
+    Ok(())
+}
+
+/// Record the events in the feed, and queue them to be announced on Discord if `discord` is set.
+/// Events that should only happen once are skipped if they already have.
+pub async fn enqueue_announcements(
+    redis: &mut Connection,
+    events: Vec<Event>,
+    discord: bool,
+) -> Result<(), Error> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for event in events {
+        let announced = event.announced_record();
+        let entry = feed::entry(&event, now)
+            .map(|entry| serde_json::to_string(&entry))
+            .transpose()?;
+        let job = if discord {
+            Some(new_job(Delivery::Discord { event }, now)?)
+        } else {
+            None
+        };
+        // Marking the event announced, queueing it, and adding it to the feed happen together,
+        // so that replicas racing on the same sync page can't do any of it twice
+        if let Some((key, member)) = announced {
+            pipe.cmd("EVAL")
+                .arg(ANNOUNCE_ONCE_SCRIPT)
+                .arg(3)
+                .arg(QUEUE_KEY)
+                .arg(key)
+                .arg(feed::FEED_KEY)
+                .arg(member)
+                .arg(now)
+                .arg(job.unwrap_or_default())
+                .arg(entry.unwrap_or_default())
+                .arg(feed::FEED_LENGTH)
+                .ignore();
+        } else if let Some(job) = job {
+            pipe.zadd(QUEUE_KEY, job, now).ignore();
+        }
+    }
+    pipe.query_async::<_, ()>(redis).await?;
+    Ok(())
+}
+
+pub fn new_job(delivery: Delivery, now: i64) -> Result<String, Error> {
+    Ok(serde_json::to_string(&Job {
+        id: new_id(),
+        delivery,
+        attempts: 0,
+        created_at: now,
+        last_error: None,
+    })?)
+}
+
+pub async fn delivery_loop(state: AppState) {
+    let mut timer = tokio::time::interval(Duration::from_secs(1));
+    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        timer.tick().await;
+        let jobs = match claim(&state).await {
+            Ok(jobs) => jobs,
+            Err(e) => {
+                error!("{e:?}");
+                continue;
+            }
+        };
+        for raw in jobs {
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = deliver(&state, raw).await {
+                    error!("{e:?}");
+                }
+            });
+        }
+    }
+}
+
+async fn claim(state: &AppState) -> Result<Vec<String>, Error> {
+    let now = chrono::Utc::now().timestamp_millis();
+    let jobs = redis::cmd("EVAL")
+        .arg(CLAIM_SCRIPT)
+        .arg(1)
+        .arg(QUEUE_KEY)
+        .arg(now)
+        .arg(now + LEASE_MS)
+        .arg(BATCH_SIZE)
+        .query_async(&mut state.redis.get().await?)
+        .await?;
+    Ok(jobs)
+}
+
+async fn deliver(state: &AppState, raw: String) -> Result<(), Error> {
+    let mut job: Job = match serde_json::from_str(&raw) {
+        Ok(job) => job,
+        Err(e) => {
+            error!("Dropping undeserializable notification job: {e:?}");
+            state
+                .redis
+                .get()
+                .await?
+                .zrem::<_, _, ()>(QUEUE_KEY, &raw)
+                .await?;
+            return Ok(());
+        }
+    };
+    let result = match &job.delivery {
+        Delivery::Discord { event } => crate::notify::announce(state, event.clone()).await,
+        Delivery::Http { sink, event } => hooks::deliver(state, sink, &job.id, event).await,
+        Delivery::Digest { digest } => digest::send(state, digest).await,
+        Delivery::Subscription { subscription, user } => {
+            subscription::send(state, subscription, user.clone()).await
+        }
+    };
+    let mut redis = state.redis.get().await?;
+    let Err(e) = result else {
+        redis.zrem::<_, _, ()>(QUEUE_KEY, &raw).await?;
+        return Ok(());
+    };
+    job.attempts += 1;
+    job.last_error = Some(e.to_string());
+    let mut pipe = redis::pipe();
+    pipe.atomic().zrem(QUEUE_KEY, &raw).ignore();
+    if job.attempts >= state.delivery_queue.max_attempts {
+        warn!(
+            "Notification {} failed {} times, giving up: {e:?}",
+            job.id, job.attempts
+        );
+        pipe.hset(DEAD_KEY, &job.id, serde_json::to_string(&job)?)
+            .ignore();
+    } else {
+        let delay = retry_after(&e)
+            .unwrap_or_default()
+            .max(state.delivery_queue.backoff(job.attempts));
+        warn!(
+            "Notification {} failed (attempt {}), retrying in {}s: {e:?}",
+            job.id,
+            job.attempts,
+            delay.as_secs()
+        );
+        #[allow(clippy::cast_possible_truncation)]
+        let due = chrono::Utc::now().timestamp_millis() + delay.as_millis() as i64;
+        pipe.zadd(QUEUE_KEY, serde_json::to_string(&job)?, due)
+            .ignore();
+    }
+    pipe.query_async::<_, ()>(&mut redis).await?;
+    Ok(())
+}
+
+/// How long Discord asked us to wait, if we were rate limited
+fn retry_after(error: &Error) -> Option<Duration> {
+    let Error::Twilight(error) = error else {
+        return None;
+    };
+    match error.kind() {
+        ErrorType::Response {
+            error: ApiError::Ratelimited(ratelimit),
+            ..
+        } => Some(Duration::from_secs_f64(ratelimit.retry_after.max(0.0))),
+        _ => None,
+    }
+}
+
+pub async fn pending(redis: &mut Connection) -> Result<u64, Error> {
+    Ok(redis.zcard(QUEUE_KEY).await?)
+}
+
+/// Jobs that ran out of attempts, oldest first
+pub async fn dead_letters(redis: &mut Connection) -> Result<Vec<Job>, Error> {
+    let raw: Vec<String> = redis.hvals(DEAD_KEY).await?;
+    let mut jobs: Vec<Job> = raw
+        .iter()
+        .filter_map(|job| serde_json::from_str(job).ok())
+        .collect();
+    jobs.sort_unstable_by_key(|job| job.created_at);
+    Ok(jobs)
+}
+
+/// Move a dead job back into the queue with a fresh set of attempts
+pub async fn replay(redis: &mut Connection, id: &str) -> Result<(), Error> {
+    let Some(raw) = redis.hget::<_, _, Option<String>>(DEAD_KEY, id).await? else {
+        return Ok(());
+    };
+    let mut job: Job = serde_json::from_str(&raw)?;
+    job.attempts = 0;
+    redis::pipe()
+        .atomic()
+        .hdel(DEAD_KEY, id)
+        .ignore()
+        .zadd(
+            QUEUE_KEY,
+            serde_json::to_string(&job)?,
+            chrono::Utc::now().timestamp_millis(),
+        )
+        .ignore()
+        .query_async::<_, ()>(redis)
+        .await?;
+    Ok(())
+}
+
+pub async fn discard(redis: &mut Connection, id: &str) -> Result<(), Error> {
+    redis.hdel::<_, _, ()>(DEAD_KEY, id).await?;
+    Ok(())
+}
+
+pub fn new_id() -> String {
+    let mut bytes = [0; 12];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base64::Engine::encode(&base64::engine::general_purpose::URL_SAFE_NO_PAD, bytes)
+}
@@ -1,12 +1,33 @@
-use crate::{util::WebhookState, AppState, Error, Player, Players, User};
-use mee6::LevelInfo;
+use crate::{
+    account::OPTOUT_KEY,
+    admin::HIDDEN_KEY,
+    digest, events,
+    hooks::{self, HookEvent},
+    notify::announced_key,
+    queue, subscription, AppState, Error, Player, Players, User,
+};
+use deadpool_redis::Connection;
 use redis::AsyncCommands;
-use std::collections::HashMap;
-use twilight_model::http::attachment::Attachment;
-use twilight_util::builder::embed::{EmbedBuilder, ImageSource};
+use std::collections::{HashMap, HashSet};
 
-const PAGE_KEY: &str = "sync:page";
-const RANK_KEY: &str = "sync:rank";
+pub const PAGE_KEY: &str = "sync:page";
+pub const RANK_KEY: &str = "sync:rank";
+/// Set of the IDs seen on the leaderboard so far this sync pass
+const SEEN_KEY: &str = "sync:seen";
+/// Set of the IDs seen on the leaderboard during the last complete sync pass
+const PREV_SEEN_KEY: &str = "sync:seen:prev";
+/// The rank after the last one handed out during the last complete sync pass
+const RANK_END_KEY: &str = "sync:rank:end";
+
+// Starts the next pass from the top, unless another page already did since page ARGV[1]
+// was handed out
+const END_PASS_SCRIPT: &str = r"
+if tonumber(redis.call('GET', KEYS[1]) or '0') > tonumber(ARGV[1]) then
+    redis.call('MSET', KEYS[1], 0, KEYS[2], 1)
+    return 1
+end
+return 0
+";
 
 #[allow(clippy::module_name_repetitions)]
 pub async fn reload_loop(state: AppState) {
@@ -42,13 +63,25 @@ async fn get_page(state: AppState) -> Result<(), Error> {
         .send()
         .await?;
     let players: Players = resp.json().await?;
-    let mut serialized_users: Vec<(String, String)> = Vec::with_capacity(2000);
+    let hidden: HashSet<String> = redis.sunion(&[OPTOUT_KEY, HIDDEN_KEY]).await?;
+    let start_rank = rank;
+    let mut serialized_users: Vec<(String, String)> = Vec::with_capacity(3000);
     let mut user_data: HashMap<u64, User> = HashMap::with_capacity(1000);
+    let mut seen: Vec<String> = Vec::with_capacity(1000);
+    // Hidden users keep their place in the ranks, but nobody gets listed there
+    let mut skipped_ranks: Vec<String> = Vec::new();
+    let mut pass_over = players.players.is_empty();
     for player in players.players {
         if player.xp < 100 {
-            redis.mset(&[(PAGE_KEY, 0), (RANK_KEY, 1)]).await?;
+            pass_over = true;
             break;
         }
+        seen.push(player.id.clone());
+        if hidden.contains(&player.id) {
+            skipped_ranks.push(format!("user.rank:{rank}"));
+            rank += 1;
+            continue;
+        }
         match player_to_user(player, rank) {
             Ok(user) => {
                 let Ok(user_string) = serde_json::to_string(&user) else {
@@ -59,54 +92,152 @@ async fn get_page(state: AppState) -> Result<(), Error> {
                     format!("user.name:{}", user.human_identifier()),
                     user.id.to_string(),
                 ));
+                serialized_users.push((format!("user.rank:{}", user.rank), user.id.to_string()));
                 serialized_users.push((format!("user.id:{}", user.id), user_string));
                 user_data.insert(user.id, user);
                 rank += 1;
             }
             Err(e) => {
                 error!("{e:?}");
-                continue;
             }
-        };
+        }
+    }
+    if !seen.is_empty() {
+        redis.sadd::<_, _, ()>(SEEN_KEY, seen).await?;
     }
-    if let Err(e) = redis.incr::<_, _, ()>(RANK_KEY, user_data.len()).await {
+    if pass_over {
+        end_pass(&state, &mut redis, page, rank).await?;
+    } else if let Err(e) = redis.incr::<_, _, ()>(RANK_KEY, rank - start_rank).await {
         error!("{e:?}");
     }
-    if let Some(webhook) = state.webhook.clone() {
-        let mut user_keys: Vec<String> = Vec::with_capacity(user_data.len());
-        for key in user_data.keys() {
-            user_keys.push(format!("user.id:{key}"));
+    if !user_data.is_empty() {
+        if let Err(e) = queue_notifications(&state, &mut redis, &user_data).await {
+            error!("{e:?}");
         }
-        if let Ok(old_users) = redis.mget::<Vec<String>, Vec<String>>(user_keys).await {
-            'userchecker: for string_user in old_users {
-                let Ok(old_user) = serde_json::from_str::<User>(&string_user) else {
-                    warn!("user failed to deserialize");
-                    continue 'userchecker;
-                };
-                let Some(new_user) = user_data.remove(&old_user.id) else {
-                    warn!("Webhook user not in user data");
-                    continue 'userchecker;
-                };
-                let old_user_level = LevelInfo::new(old_user.xp).level();
-                let new_user_level = LevelInfo::new(new_user.xp).level();
-                if new_user_level >= 5 && old_user_level < 5 {
-                    let state = state.clone();
-                    let whstate = webhook.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = send_hook(&state, &whstate, new_user, new_user_level).await
-                        {
-                            error!("{e:?}");
-                        }
-                    });
-                }
-            }
+    }
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    if !serialized_users.is_empty() {
+        pipe.mset(&serialized_users).ignore();
+    }
+    if !skipped_ranks.is_empty() {
+        pipe.del(skipped_ranks).ignore();
+    }
+    pipe.query_async::<_, ()>(&mut redis).await?;
+    Ok(())
+}
+
+/// Diff a page against what we had stored, queueing whatever should be announced
+async fn queue_notifications(
+    state: &AppState,
+    redis: &mut Connection,
+    user_data: &HashMap<u64, User>,
+) -> Result<(), Error> {
+    let discord = state
+        .notifier
+        .announces_individually(state.webhook.as_ref());
+    let user_keys: Vec<String> = user_data.keys().map(|id| format!("user.id:{id}")).collect();
+    let old_users: Vec<Option<String>> = redis.mget(user_keys).await?;
+    let mut old_user_data: HashMap<u64, User> = HashMap::with_capacity(old_users.len());
+    for string_user in old_users.into_iter().flatten() {
+        let Ok(old_user) = serde_json::from_str::<User>(&string_user) else {
+            warn!("user failed to deserialize");
+            continue;
+        };
+        old_user_data.insert(old_user.id, old_user);
+    }
+    let mut seed = redis::pipe();
+    for (id, levels) in state.notifier.already_reached(&old_user_data, user_data) {
+        seed.sadd(announced_key(id), levels).ignore();
+    }
+    seed.query_async::<_, ()>(redis).await?;
+    let announcements = state.notifier.diff(&old_user_data, user_data);
+    queue::enqueue_announcements(redis, announcements, discord).await?;
+    if state.webhook.is_some() {
+        // A failed alert check shouldn't hold up the digest or the webhooks
+        if let Err(e) = subscription::check(redis, &old_user_data, user_data).await {
+            error!("Failed to check subscriptions: {e:?}");
         }
     }
-    redis.mset::<String, String, ()>(&serialized_users).await?;
+    if let Some(schedule) = &state.notifier.digest {
+        digest::record(redis, schedule, &old_user_data, user_data).await?;
+    }
+    let events = hooks::diff(&old_user_data, user_data);
+    queue::enqueue(redis, hooks::deliveries(&state.notifier.sinks, &events)).await?;
+    publish(redis, &events).await;
     Ok(())
 }
 
-fn player_to_user(player: Player, rank: i64) -> Result<User, Error> {
+/// Start the next sync pass, if nobody beat us to it, and report who wasn't seen in this one.
+/// `rank` is the rank after the last one this pass handed out.
+async fn end_pass(
+    state: &AppState,
+    redis: &mut Connection,
+    page: i64,
+    rank: i64,
+) -> Result<(), Error> {
+    let ended: bool = redis::cmd("EVAL")
+        .arg(END_PASS_SCRIPT)
+        .arg(2)
+        .arg(PAGE_KEY)
+        .arg(RANK_KEY)
+        .arg(page)
+        .query_async(redis)
+        .await?;
+    if !ended {
+        return Ok(());
+    }
+    debug!("Sync pass ended at page {page}");
+    // If the leaderboard shrank, the ranks past its new end still point at whoever held them
+    let previous_end: Option<i64> = redis.getset(RANK_END_KEY, rank).await?;
+    if let Some(previous_end) = previous_end.filter(|end| *end > rank) {
+        let stale_ranks: Vec<String> = (rank..previous_end)
+            .map(|rank| format!("user.rank:{rank}"))
+            .collect();
+        redis.del::<_, ()>(stale_ranks).await?;
+    }
+    let departed: Vec<u64> = redis.sdiff(&[PREV_SEEN_KEY, SEEN_KEY]).await?;
+    let hidden: HashSet<u64> = redis.sunion(&[OPTOUT_KEY, HIDDEN_KEY]).await?;
+    let departed = announced_departures(departed, &hidden);
+    if redis.exists(SEEN_KEY).await? {
+        redis.rename::<_, _, ()>(SEEN_KEY, PREV_SEEN_KEY).await?;
+    } else {
+        redis.del::<_, ()>(PREV_SEEN_KEY).await?;
+    }
+    let mut events = Vec::with_capacity(departed.len() + 1);
+    if !departed.is_empty() {
+        let user_keys: Vec<String> = departed.iter().map(|id| format!("user.id:{id}")).collect();
+        let users: Vec<Option<String>> = redis.mget(user_keys).await?;
+        events.extend(departed.into_iter().zip(users).map(|(user_id, user)| {
+            HookEvent::UserDeparted {
+                user_id,
+                user: user.and_then(|user| serde_json::from_str(&user).ok()),
+            }
+        }));
+    }
+    events.push(HookEvent::SyncComplete { pages: page + 1 });
+    queue::enqueue(redis, hooks::deliveries(&state.notifier.sinks, &events)).await?;
+    publish(redis, &events).await;
+    Ok(())
+}
+
+/// Users who left the leaderboard, minus those who opted out or were hidden, who mustn't show up
+/// in events even when they leave
+fn announced_departures(departed: Vec<u64>, hidden: &HashSet<u64>) -> Vec<u64> {
+    departed
+        .into_iter()
+        .filter(|id| !hidden.contains(id))
+        .collect()
+}
+
+/// The live stream is best-effort, so it failing mustn't stop anything else
+async fn publish(redis: &mut Connection, events: &[HookEvent]) {
+    if let Err(e) = events::publish(redis, events).await {
+        warn!("Failed to publish sync events: {e:?}");
+    }
+}
+
+fn player_to_user(player: Player, rank: i64) -> Result<User, std::num::ParseIntError> {
     let id = player.id.parse::<u64>()?;
     let last_updated = Some(chrono::offset::Utc::now().timestamp_millis());
     let user = User {
@@ -122,47 +253,14 @@ fn player_to_user(player: Player, rank: i64) -> Result<User, Error> {
     Ok(user)
 }
 
-async fn send_hook(
-    state: &AppState,
-    webhook: &WebhookState,
-    user: User,
-    level: u64,
-) -> Result<(), Error> {
-    let request = format!("{0}/card?id={1} <@{1}>", &*state.root_url, user.id);
-    let embed = EmbedBuilder::new()
-        .image(ImageSource::attachment("card.png")?)
-        .thumbnail(ImageSource::url(format!(
-            "{}/search6.png",
-            &*state.root_url
-        ))?)
-        .description(format!(
-            "User {} (<@{}>) has reached level {}```{}```",
-            user.human_identifier(),
-            user.id,
-            level,
-            request
-        ))
-        .build();
-    let card_svg = crate::util::user_context(state, user).await?;
-    let card_raster = state.svg.render(card_svg).await?;
-    let card = Attachment {
-        description: None,
-        file: card_raster,
-        filename: "card.png".to_string(),
-        id: 0,
-    };
-    let mut hook_builder = webhook
-        .client
-        .execute_webhook(webhook.marker, &webhook.token)
-        .username("search6 notifier")?
-        .avatar_url("https://search6.valk.sh/mee6_bad.png");
-    if let Some(thread_id) = webhook.thread {
-        hook_builder = hook_builder.thread_id(thread_id);
-    };
-    hook_builder
-        .content(&request)?
-        .attachments(&[card])?
-        .embeds(&[embed])?
-        .await?;
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hidden_users_dont_depart_publicly() {
+        let hidden = HashSet::from([2, 4]);
+        assert_eq!(announced_departures(vec![1, 2, 3, 4], &hidden), [1, 3]);
+        assert!(announced_departures(vec![2], &hidden).is_empty());
+    }
 }
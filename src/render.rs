@@ -0,0 +1,130 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::extract::State;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use xpd_rank_card::SvgState;
+
+use crate::{leaderboard::LeaderboardState, AppState, Error, User};
+
+/// Bounds how many rasterizations run at once. Requests beyond the limit wait in line
+/// for up to `max_wait`, after which they are turned away with a 503.
+#[derive(Clone)]
+pub struct RenderQueue {
+    permits: Arc<Semaphore>,
+    concurrency: usize,
+    max_wait: Duration,
+    waiting: Arc<AtomicUsize>,
+    rejected: Arc<AtomicU64>,
+}
+
+impl RenderQueue {
+    #[must_use]
+    pub fn new(concurrency: usize, max_wait: Duration) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(concurrency)),
+            concurrency,
+            max_wait,
+            waiting: Arc::new(AtomicUsize::new(0)),
+            rejected: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let concurrency = std::env::var("RENDER_CONCURRENCY").map_or_else(
+            |_| std::thread::available_parallelism().map_or(4, std::num::NonZeroUsize::get),
+            |v| {
+                v.parse::<std::num::NonZeroUsize>()
+                    .expect("Expected a positive number in RENDER_CONCURRENCY")
+                    .get()
+            },
+        );
+        let max_wait_ms = std::env::var("RENDER_MAX_WAIT_MS").map_or(5000, |v| {
+            v.parse().expect("Expected a number in RENDER_MAX_WAIT_MS")
+        });
+        Self::new(concurrency, Duration::from_millis(max_wait_ms))
+    }
+
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit, Error> {
+        let waiting = Waiting::new(&self.waiting);
+        let permit =
+            tokio::time::timeout(self.max_wait, self.permits.clone().acquire_owned()).await;
+        drop(waiting);
+        if let Ok(Ok(permit)) = permit {
+            Ok(permit)
+        } else {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            warn!("Render queue full, rejecting render");
+            Err(Error::RenderQueueFull(self.max_wait.as_secs().max(1)))
+        }
+    }
+
+    pub async fn render_card(
+        &self,
+        svg: &SvgState,
+        ctx: xpd_rank_card::Context,
+    ) -> Result<Vec<u8>, Error> {
+        let _permit = self.acquire().await?;
+        Ok(svg.render(ctx).await?)
+    }
+
+    pub async fn render_leaderboard(
+        &self,
+        leaderboard: &LeaderboardState,
+        root_url: &str,
+        users: &[User],
+    ) -> Result<Vec<u8>, Error> {
+        let _permit = self.acquire().await?;
+        leaderboard.render(root_url, users).await
+    }
+
+    fn in_flight(&self) -> usize {
+        self.concurrency - self.permits.available_permits()
+    }
+}
+
+/// Counts a request as waiting for as long as it's alive, even if its handler is dropped mid-wait
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn new(count: &'a AtomicUsize) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[allow(clippy::unused_async)]
+pub async fn metrics(State(state): State<AppState>) -> ([(&'static str, &'static str); 1], String) {
+    let queue = &state.render_queue;
+    let out = format!(
+        "# HELP search6_render_queue_depth Renders waiting for a free slot.
+# TYPE search6_render_queue_depth gauge
+search6_render_queue_depth {}
+# HELP search6_render_in_flight Renders currently running.
+# TYPE search6_render_in_flight gauge
+search6_render_in_flight {}
+# HELP search6_render_concurrency Maximum concurrent renders.
+# TYPE search6_render_concurrency gauge
+search6_render_concurrency {}
+# HELP search6_render_rejected_total Renders rejected after waiting too long.
+# TYPE search6_render_rejected_total counter
+search6_render_rejected_total {}
+",
+        queue.waiting.load(Ordering::Relaxed),
+        queue.in_flight(),
+        queue.concurrency,
+        queue.rejected.load(Ordering::Relaxed),
+    );
+    ([("Content-Type", "text/plain; version=0.0.4")], out)
+}
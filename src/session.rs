@@ -0,0 +1,187 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, State},
+    http::request::Parts,
+    response::Redirect,
+};
+use base64::Engine;
+use deadpool_redis::Connection;
+use rand::RngCore;
+use redis::AsyncCommands;
+use ring::hmac;
+
+use crate::{AppState, Error};
+
+const COOKIE_NAME: &str = "search6_session";
+/// Sessions last 30 days
+const SESSION_TTL: usize = 60 * 60 * 24 * 30;
+
+/// A logged-in user, extracted from the signed session cookie.
+/// Use `Option<Session>` for pages that work with or without a login.
+pub struct Session {
+    pub token: String,
+    pub user_id: u64,
+    /// Whether the user was verified to be in the guild when they logged in
+    pub member: bool,
+}
+
+/// A logged-in user who has been verified to be in the guild.
+/// Member-only features are unavailable when `GUILD_CHECK` is not set.
+pub struct Member(pub Session);
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct SessionData {
+    user_id: u64,
+    member: bool,
+}
+
+/// Loads the session signing key from `SESSION_SECRET`, or makes one up,
+/// which logs everyone out on every restart.
+pub fn get_session_key() -> hmac::Key {
+    let secret = std::env::var("SESSION_SECRET").map_or_else(
+        |_| {
+            warn!("SESSION_SECRET not set, sessions will not survive restarts");
+            let mut secret = vec![0; 32];
+            rand::thread_rng().fill_bytes(&mut secret);
+            secret
+        },
+        String::into_bytes,
+    );
+    hmac::Key::new(hmac::HMAC_SHA256, &secret)
+}
+
+/// Creates a new session for `user_id`, returning the `Set-Cookie` header value for it.
+pub async fn create(state: &AppState, user_id: u64, member: bool) -> Result<String, Error> {
+    let mut token_bytes = [0; 32];
+    rand::thread_rng().fill_bytes(&mut token_bytes);
+    let token = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(token_bytes);
+    let mut redis = state.redis.get().await?;
+    redis
+        .set_ex::<_, _, ()>(
+            format!("session:{token}"),
+            serde_json::to_string(&SessionData { user_id, member })?,
+            SESSION_TTL,
+        )
+        .await?;
+    // Track each user's sessions so they can all be ended at once
+    let sessions_key = format!("user.sessions:{user_id}");
+    redis.sadd::<_, _, ()>(&sessions_key, &token).await?;
+    redis.expire::<_, ()>(&sessions_key, SESSION_TTL).await?;
+    let signature = hmac::sign(&state.session_key, token.as_bytes());
+    let signature = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(signature);
+    Ok(cookie(state, &format!("{token}.{signature}"), SESSION_TTL))
+}
+
+/// Ends every session belonging to `user_id`.
+pub async fn destroy_all(redis: &mut Connection, user_id: u64) -> Result<(), Error> {
+    let sessions_key = format!("user.sessions:{user_id}");
+    let tokens: Vec<String> = redis.smembers(&sessions_key).await?;
+    let mut keys: Vec<String> = tokens
+        .iter()
+        .map(|token| format!("session:{token}"))
+        .collect();
+    keys.push(sessions_key);
+    redis.del::<_, ()>(keys).await?;
+    Ok(())
+}
+
+/// A `Set-Cookie` header value which logs the browser out
+pub fn clear_cookie(state: &AppState) -> String {
+    cookie(state, "", 0)
+}
+
+fn cookie(state: &AppState, value: &str, max_age: usize) -> String {
+    let secure = if state.root_url.starts_with("https://") {
+        "; Secure"
+    } else {
+        ""
+    };
+    format!("{COOKIE_NAME}={value}; Path=/; HttpOnly; SameSite=Lax; Max-Age={max_age}{secure}")
+}
+
+/// Returns the token from a cookie value if its signature is valid.
+fn verify<'a>(key: &hmac::Key, value: &'a str) -> Option<&'a str> {
+    let (token, signature) = value.split_once('.')?;
+    let signature = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(signature)
+        .ok()?;
+    hmac::verify(key, token.as_bytes(), &signature).ok()?;
+    Some(token)
+}
+
+fn session_cookie(parts: &Parts) -> Option<&str> {
+    parts
+        .headers
+        .get_all("Cookie")
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find_map(|(name, value)| (name == COOKIE_NAME).then_some(value))
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Session {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Error> {
+        let token = session_cookie(parts)
+            .and_then(|value| verify(&state.session_key, value))
+            .ok_or(Error::NotLoggedIn)?;
+        let data: Option<String> = state
+            .redis
+            .get()
+            .await?
+            .get(format!("session:{token}"))
+            .await?;
+        let data: SessionData = data
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .ok_or(Error::NotLoggedIn)?;
+        Ok(Self {
+            token: token.to_string(),
+            user_id: data.user_id,
+            member: data.member,
+        })
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Member {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Error> {
+        let session = Session::from_request_parts(parts, state).await?;
+        if state.guild_check.is_none() {
+            return Err(Error::GuildCheckDisabled);
+        }
+        if !session.member {
+            return Err(Error::NotGuildMember);
+        }
+        Ok(Self(session))
+    }
+}
+
+/// Shows the logged-in user's own page, logging them in first if needed.
+#[allow(clippy::unused_async)]
+pub async fn me(session: Option<Session>) -> Redirect {
+    session.map_or_else(
+        || Redirect::to("/o"),
+        |session| Redirect::to(&format!("/?id={}&userexists=true", session.user_id)),
+    )
+}
+
+pub async fn logout(
+    State(state): State<AppState>,
+    session: Option<Session>,
+) -> Result<([(&'static str, String); 1], Redirect), Error> {
+    if let Some(session) = session {
+        let mut redis = state.redis.get().await?;
+        redis
+            .del::<_, ()>(format!("session:{}", session.token))
+            .await?;
+        redis
+            .srem::<_, _, ()>(format!("user.sessions:{}", session.user_id), &session.token)
+            .await?;
+    }
+    Ok(([("Set-Cookie", clear_cookie(&state))], Redirect::to("/")))
+}
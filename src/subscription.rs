@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Form, State},
+    response::Redirect,
+};
+use deadpool_redis::Connection;
+use mee6::LevelInfo;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    notify::{self, Message},
+    queue::{self, Delivery},
+    session::Session,
+    util, AppState, Error, User,
+};
+
+/// Hash of subscription ID to every pending [`Subscription`]
+const SUBSCRIPTIONS_KEY: &str = "subscriptions";
+/// How many alerts one user can have waiting at once
+const MAX_SUBSCRIPTIONS: usize = 10;
+
+// Unless another replica already has, removes the subscription ARGV[1] from KEYS[1], its
+// subscriber's set KEYS[2] and its watched user's set KEYS[3], and queues the job ARGV[3] due at
+// ARGV[2] on KEYS[4]
+const FIRE_SCRIPT: &str = r"
+if redis.call('HDEL', KEYS[1], ARGV[1]) == 1 then
+    redis.call('SREM', KEYS[2], ARGV[1])
+    redis.call('SREM', KEYS[3], ARGV[1])
+    redis.call('ZADD', KEYS[4], ARGV[2], ARGV[3])
+end
+";
+
+// Unless the subscriber's set KEYS[2] already has ARGV[3] subscriptions in it, adds the
+// subscription ARGV[2] with ID ARGV[1] to KEYS[1], KEYS[2] and its watched user's set KEYS[3]
+const SUBSCRIBE_SCRIPT: &str = r"
+if redis.call('SCARD', KEYS[2]) >= tonumber(ARGV[3]) then
+    return 0
+end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+redis.call('SADD', KEYS[2], ARGV[1])
+redis.call('SADD', KEYS[3], ARGV[1])
+return 1
+";
+
+/// Set of the IDs of the subscriptions `id` has waiting
+pub fn subscriptions_key(id: u64) -> String {
+    format!("user.subscriptions:{id}")
+}
+
+/// Set of the IDs of the subscriptions waiting on `id`'s progress
+fn watchers_key(id: u64) -> String {
+    format!("user.watchers:{id}")
+}
+
+/// A one-off alert a user asked for, removed once it fires
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub id: String,
+    pub subscriber: u64,
+    /// The user whose progress is being watched, who may be the subscriber themselves
+    pub watched: u64,
+    #[serde(flatten)]
+    pub alert: Alert,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "alert", rename_all = "snake_case")]
+pub enum Alert {
+    Level {
+        level: u64,
+    },
+    Rank {
+        rank: i64,
+    },
+    /// The watched user moves from below the subscriber to above them
+    PassesMe,
+}
+
+impl Subscription {
+    /// What the subscription is waiting for, with `name` being the watched user's
+    fn pending_description(&self, name: &str) -> String {
+        let own = self.watched == self.subscriber;
+        match (&self.alert, own) {
+            (Alert::Level { level }, true) => format!("When you reach level {level}"),
+            (Alert::Level { level }, false) => format!("When {name} reaches level {level}"),
+            (Alert::Rank { rank }, true) => format!("When you reach rank #{rank}"),
+            (Alert::Rank { rank }, false) => format!("When {name} reaches rank #{rank}"),
+            (Alert::PassesMe, _) => format!("When {name} passes you"),
+        }
+    }
+
+    /// The alert, now that `user` has done what was being waited for
+    fn description(&self, user: &User) -> String {
+        let who = if self.watched == self.subscriber {
+            "You have".to_string()
+        } else {
+            format!("{} has", user.human_identifier())
+        };
+        match &self.alert {
+            Alert::Level { level } => format!("{who} reached level {level}!"),
+            Alert::Rank { rank } => format!("{who} reached rank #{rank}!"),
+            Alert::PassesMe => format!("{who} passed you, and is now rank #{}!", user.rank),
+        }
+    }
+
+    /// Whether the watched user went from `old` to `new` in a way that fires this subscription.
+    /// `subscriber` is the subscriber's rank before and after, if we know it.
+    fn fired(&self, old: &User, new: &User, subscriber: Option<(i64, i64)>) -> bool {
+        match &self.alert {
+            Alert::Level { level } => {
+                LevelInfo::new(old.xp).level() < *level && LevelInfo::new(new.xp).level() >= *level
+            }
+            Alert::Rank { rank } => old.rank > *rank && new.rank <= *rank,
+            Alert::PassesMe => subscriber
+                .is_some_and(|(old_rank, new_rank)| old.rank > old_rank && new.rank < new_rank),
+        }
+    }
+}
+
+/// Every subscription `id` has waiting
+pub async fn get_subscriptions(
+    redis: &mut Connection,
+    id: u64,
+) -> Result<Vec<Subscription>, Error> {
+    let ids: Vec<String> = redis.smembers(subscriptions_key(id)).await?;
+    lookup(redis, ids).await
+}
+
+async fn lookup(redis: &mut Connection, ids: Vec<String>) -> Result<Vec<Subscription>, Error> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let raw: Vec<Option<String>> = redis.hget(SUBSCRIPTIONS_KEY, ids).await?;
+    Ok(raw
+        .into_iter()
+        .flatten()
+        .filter_map(|subscription| serde_json::from_str(&subscription).ok())
+        .collect())
+}
+
+/// Check a page of users before and after a sync against the subscriptions watching them,
+/// queueing the alerts for the ones that fired
+pub async fn check(
+    redis: &mut Connection,
+    old_users: &HashMap<u64, User>,
+    new_users: &HashMap<u64, User>,
+) -> Result<(), Error> {
+    let watchers: Vec<String> = new_users
+        .keys()
+        .filter(|id| old_users.contains_key(id))
+        .map(|id| watchers_key(*id))
+        .collect();
+    if watchers.is_empty() {
+        return Ok(());
+    }
+    let ids: Vec<String> = redis.sunion(watchers).await?;
+    let subscriptions: Vec<Subscription> = lookup(redis, ids)
+        .await?
+        .into_iter()
+        .filter(|s| old_users.contains_key(&s.watched) && new_users.contains_key(&s.watched))
+        .collect();
+    if subscriptions.is_empty() {
+        return Ok(());
+    }
+    // Subscribers waiting to be passed by someone on this page may be further down the leaderboard
+    let elsewhere: Vec<u64> = subscriptions
+        .iter()
+        .filter(|s| matches!(s.alert, Alert::PassesMe) && !new_users.contains_key(&s.subscriber))
+        .map(|s| s.subscriber)
+        .collect();
+    let mut ranks: HashMap<u64, (i64, i64)> = HashMap::new();
+    if !elsewhere.is_empty() {
+        let user_keys: Vec<String> = elsewhere.iter().map(|id| format!("user.id:{id}")).collect();
+        let users: Vec<Option<String>> = redis.mget(user_keys).await?;
+        for user in users.into_iter().flatten() {
+            if let Ok(user) = serde_json::from_str::<User>(&user) {
+                ranks.insert(user.id, (user.rank, user.rank));
+            }
+        }
+    }
+    for (id, new_user) in new_users {
+        let old_rank = old_users.get(id).map_or(new_user.rank, |old| old.rank);
+        ranks.insert(*id, (old_rank, new_user.rank));
+    }
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut pipe = redis::pipe();
+    for subscription in subscriptions {
+        let old = &old_users[&subscription.watched];
+        let new = &new_users[&subscription.watched];
+        if !subscription.fired(old, new, ranks.get(&subscription.subscriber).copied()) {
+            continue;
+        }
+        let subscriber_key = subscriptions_key(subscription.subscriber);
+        let watched_key = watchers_key(subscription.watched);
+        let id = subscription.id.clone();
+        let job = queue::new_job(
+            Delivery::Subscription {
+                subscription,
+                user: new.clone(),
+            },
+            now,
+        )?;
+        pipe.cmd("EVAL")
+            .arg(FIRE_SCRIPT)
+            .arg(4)
+            .arg(SUBSCRIPTIONS_KEY)
+            .arg(subscriber_key)
+            .arg(watched_key)
+            .arg(queue::QUEUE_KEY)
+            .arg(id)
+            .arg(now)
+            .arg(job)
+            .ignore();
+    }
+    pipe.query_async::<_, ()>(redis).await?;
+    Ok(())
+}
+
+/// Ping the subscriber about `user`, through `WEBHOOK`
+pub async fn send(state: &AppState, subscription: &Subscription, user: User) -> Result<(), Error> {
+    let webhook = state.webhook.as_ref().ok_or(Error::WebhookDisabled)?;
+    let message = Message {
+        content: format!("<@{}>", subscription.subscriber),
+        title: None,
+        description: subscription.description(&user),
+        footer: None,
+    };
+    notify::send_hook(state, webhook, user, &message, state.notifier.dry_run).await
+}
+
+/// Removes everyone's subscriptions to `id`'s progress, and `id`'s own subscriptions
+pub async fn remove_all(redis: &mut Connection, id: u64) -> Result<(), Error> {
+    let ids: Vec<String> = redis
+        .sunion(&[subscriptions_key(id), watchers_key(id)])
+        .await?;
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for subscription in lookup(redis, ids).await? {
+        pipe.hdel(SUBSCRIPTIONS_KEY, &subscription.id)
+            .ignore()
+            .srem(subscriptions_key(subscription.subscriber), &subscription.id)
+            .ignore()
+            .srem(watchers_key(subscription.watched), &subscription.id)
+            .ignore();
+    }
+    pipe.del(&[subscriptions_key(id), watchers_key(id)])
+        .ignore();
+    pipe.query_async::<_, ()>(redis).await?;
+    Ok(())
+}
+
+/// A pending subscription, as shown on the account page
+#[derive(Serialize)]
+pub struct SubscriptionView {
+    id: String,
+    description: String,
+}
+
+/// Describe `id`'s subscriptions, naming the users they watch
+pub async fn views(redis: &mut Connection, id: u64) -> Result<Vec<SubscriptionView>, Error> {
+    let subscriptions = get_subscriptions(redis, id).await?;
+    if subscriptions.is_empty() {
+        return Ok(Vec::new());
+    }
+    let user_keys: Vec<String> = subscriptions
+        .iter()
+        .map(|s| format!("user.id:{}", s.watched))
+        .collect();
+    let users: Vec<Option<String>> = redis.mget(user_keys).await?;
+    Ok(subscriptions
+        .into_iter()
+        .zip(users)
+        .map(|(subscription, user)| {
+            let name = user
+                .and_then(|user| serde_json::from_str::<User>(&user).ok())
+                .map_or_else(
+                    || subscription.watched.to_string(),
+                    |u| u.human_identifier(),
+                );
+            SubscriptionView {
+                description: subscription.pending_description(&name),
+                id: subscription.id,
+            }
+        })
+        .collect())
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn subscribe(
+    State(state): State<AppState>,
+    session: Session,
+    Form(form): Form<SubscribeForm>,
+) -> Result<Redirect, Error> {
+    if state.webhook.is_none() {
+        return Err(Error::WebhookDisabled);
+    }
+    let me = util::get_user(&state, session.user_id.to_string(), true).await?;
+    let watched = match form.user.trim() {
+        "" => me.clone(),
+        other => util::get_user(&state, other.to_string(), false).await?,
+    };
+    let alert = match form.alert.as_str() {
+        "level" => {
+            let level: u64 = form.value.trim().parse()?;
+            if LevelInfo::new(watched.xp).level() >= level {
+                return Err(Error::InvalidSubscription(
+                    "That level has already been reached",
+                ));
+            }
+            Alert::Level { level }
+        }
+        "rank" => {
+            let rank: i64 = form.value.trim().parse()?;
+            if rank < 1 {
+                return Err(Error::InvalidSubscription("Ranks start at 1"));
+            }
+            if watched.rank <= rank {
+                return Err(Error::InvalidSubscription(
+                    "That rank has already been reached",
+                ));
+            }
+            Alert::Rank { rank }
+        }
+        "passes_me" => {
+            if watched.id == me.id {
+                return Err(Error::InvalidSubscription("You can't pass yourself"));
+            }
+            if watched.rank < me.rank {
+                return Err(Error::InvalidSubscription("They are already ahead of you"));
+            }
+            Alert::PassesMe
+        }
+        _ => return Err(Error::InvalidSubscription("Unknown kind of alert")),
+    };
+    let subscription = Subscription {
+        id: queue::new_id(),
+        subscriber: me.id,
+        watched: watched.id,
+        alert,
+    };
+    // Counting and adding happen together, so that a burst of requests can't go over the limit
+    let added: bool = redis::cmd("EVAL")
+        .arg(SUBSCRIBE_SCRIPT)
+        .arg(3)
+        .arg(SUBSCRIPTIONS_KEY)
+        .arg(subscriptions_key(me.id))
+        .arg(watchers_key(watched.id))
+        .arg(&subscription.id)
+        .arg(serde_json::to_string(&subscription)?)
+        .arg(MAX_SUBSCRIPTIONS)
+        .query_async(&mut state.redis.get().await?)
+        .await?;
+    if !added {
+        return Err(Error::InvalidSubscription(
+            "You can only have 10 alerts waiting at once",
+        ));
+    }
+    info!("User {} subscribed to {}", me.id, watched.id);
+    Ok(Redirect::to("/account"))
+}
+
+#[allow(clippy::missing_errors_doc)]
+pub async fn unsubscribe(
+    State(state): State<AppState>,
+    session: Session,
+    Form(form): Form<UnsubscribeForm>,
+) -> Result<Redirect, Error> {
+    let mut redis = state.redis.get().await?;
+    // Only remove it if it's theirs
+    let removed: bool = redis
+        .srem(subscriptions_key(session.user_id), &form.id)
+        .await?;
+    if !removed {
+        return Ok(Redirect::to("/account"));
+    }
+    let raw: Option<String> = redis.hget(SUBSCRIPTIONS_KEY, &form.id).await?;
+    let mut pipe = redis::pipe();
+    pipe.atomic().hdel(SUBSCRIPTIONS_KEY, &form.id).ignore();
+    if let Some(subscription) = raw.and_then(|s| serde_json::from_str::<Subscription>(&s).ok()) {
+        pipe.srem(watchers_key(subscription.watched), &form.id)
+            .ignore();
+    }
+    pipe.query_async::<_, ()>(&mut redis).await?;
+    Ok(Redirect::to("/account"))
+}
+
+#[derive(Deserialize)]
+pub struct SubscribeForm {
+    alert: String,
+    /// Slug or ID of the user to watch, or blank to watch yourself
+    #[serde(default)]
+    user: String,
+    #[serde(default)]
+    value: String,
+}
+
+#[derive(Deserialize)]
+pub struct UnsubscribeForm {
+    id: String,
+}
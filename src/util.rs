@@ -14,7 +14,7 @@ use twilight_model::id::{
 use crate::{AppState, Error, User};
 
 pub async fn get_avatar_data(state: &AppState, user: &User) -> Result<String, Error> {
-    let url = get_avatar_url(user.id, &user.avatar, false);
+    let url = get_avatar_url(user.id, user.avatar.as_deref(), false);
     let png = state.http.get(url).send().await?.bytes().await?;
     let data = format!(
         "data:image/png;base64,{}",
@@ -28,7 +28,7 @@ pub async fn get_user(state: &AppState, id: String, user_exists: bool) -> Result
     let user_id = if id.chars().all(|c| c.is_ascii_digit()) {
         id
     } else {
-        let slug_key = format!("user.slug:{id}");
+        let slug_key = format!("user.slug:{}", id.trim().to_ascii_lowercase());
         let id: Option<String> = redis.get(slug_key).await?;
         if user_exists {
             id.ok_or(Error::NotLevelFive)?
@@ -36,6 +36,15 @@ pub async fn get_user(state: &AppState, id: String, user_exists: bool) -> Result
             id.ok_or(Error::UnknownId)?
         }
     };
+    if redis
+        .sismember(crate::account::OPTOUT_KEY, &user_id)
+        .await?
+    {
+        return Err(Error::OptedOut);
+    }
+    if redis.sismember(crate::admin::HIDDEN_KEY, &user_id).await? {
+        return Err(Error::Hidden);
+    }
     let data_string_optional: Option<String> = redis.get(format!("user.id:{user_id}")).await?;
     let data_string = if user_exists {
         data_string_optional.ok_or(Error::NotLevelFive)?
@@ -45,7 +54,7 @@ pub async fn get_user(state: &AppState, id: String, user_exists: bool) -> Result
     Ok(serde_json::from_str(&data_string)?)
 }
 
-pub fn get_avatar_url(id: u64, hash: &Option<String>, allowgif: bool) -> String {
+pub fn get_avatar_url(id: u64, hash: Option<&str>, allowgif: bool) -> String {
     let Some(hash) = hash else {
         return format!(
             "https://cdn.discordapp.com/embed/avatars/{}.png?width=256&height=256",
@@ -80,20 +89,38 @@ pub fn get_oauth(root_url: &str) -> Option<BasicClient> {
     } else if client_secret.is_some() && client_id.is_none() {
         panic!("if CLIENT_SECRET is set, CLIENT_ID must also be set!")
     }
+    let auth_url = env_or(
+        "OAUTH_AUTHORIZE_URL",
+        "https://discord.com/oauth2/authorize",
+    );
+    let token_url = env_or("OAUTH_TOKEN_URL", "https://discord.com/api/oauth2/token");
+    let revoke_url = env_or(
+        "OAUTH_REVOKE_URL",
+        "https://discord.com/api/oauth2/token/revoke",
+    );
     let oauth = oauth2::basic::BasicClient::new(
         ClientId::new(client_id?),
         Some(ClientSecret::new(client_secret?)),
-        AuthUrl::new("https://discord.com/oauth2/authorize".to_string()).unwrap(),
-        Some(TokenUrl::new("https://discord.com/api/oauth2/token".to_string()).unwrap()),
-    )
-    .set_revocation_uri(
-        RevocationUrl::new("https://discord.com/api/oauth2/token/revoke".to_string()).unwrap(),
+        AuthUrl::new(auth_url).expect("Invalid OAUTH_AUTHORIZE_URL"),
+        Some(TokenUrl::new(token_url).expect("Invalid OAUTH_TOKEN_URL")),
     )
+    .set_revocation_uri(RevocationUrl::new(revoke_url).expect("Invalid OAUTH_REVOKE_URL"))
     // Set the URL the user will be redirected to after the authorization process.
     .set_redirect_uri(RedirectUrl::new(format!("{root_url}/oc")).unwrap());
     Some(oauth)
 }
 
+/// The base URL for Discord API calls made on behalf of a logged-in user, without a trailing slash
+pub fn get_discord_api() -> String {
+    env_or("DISCORD_API_URL", "https://discord.com/api/v10")
+        .trim_end_matches('/')
+        .to_string()
+}
+
+fn env_or(name: &str, default: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
 pub async fn get_user_context(
     state: &AppState,
     id: String,
@@ -104,9 +131,14 @@ pub async fn get_user_context(
 }
 
 pub async fn user_context(state: &AppState, user: User) -> Result<xpd_rank_card::Context, Error> {
+    let avatar = crate::util::get_avatar_data(state, &user).await?;
+    Ok(card_context(&user, avatar))
+}
+
+pub fn card_context(user: &User, avatar: String) -> xpd_rank_card::Context {
     let level_info = mee6::LevelInfo::new(user.xp);
     #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
-    let ctx = xpd_rank_card::Context {
+    xpd_rank_card::Context {
         level: level_info.level(),
         rank: user.rank,
         name: user.username.clone(),
@@ -115,11 +147,37 @@ pub async fn user_context(state: &AppState, user: User) -> Result<xpd_rank_card:
         current: level_info.xp(),
         needed: mee6::xp_needed_for_level(level_info.level() + 1),
         toy: None,
-        avatar: crate::util::get_avatar_data(state, &user).await?,
+        avatar,
         font: xpd_rank_card::Font::Mojang,
         colors: xpd_rank_card::colors::Colors::default(),
-    };
-    Ok(ctx)
+    }
+}
+
+/// How to check that a logged-in user is in the configured guild, if at all
+#[derive(Clone, Copy)]
+pub enum GuildCheck {
+    /// List the user's guilds with the `guilds` scope
+    Guilds,
+    /// Fetch the user's member object with the `guilds.members.read` scope
+    Members,
+}
+
+impl GuildCheck {
+    pub const fn scope(self) -> &'static str {
+        match self {
+            Self::Guilds => "guilds",
+            Self::Members => "guilds.members.read",
+        }
+    }
+}
+
+pub fn get_guild_check() -> Option<GuildCheck> {
+    let check = std::env::var("GUILD_CHECK").ok()?;
+    match check.as_str() {
+        "guilds" => Some(GuildCheck::Guilds),
+        "members" => Some(GuildCheck::Members),
+        _ => panic!("GUILD_CHECK must be either `guilds` or `members`"),
+    }
 }
 
 #[derive(Clone)]
@@ -135,15 +193,24 @@ pub fn get_webhook() -> Option<WebhookState> {
     let thread: Option<Id<ChannelMarker>> = std::env::var("THREAD_ID")
         .ok()
         .map(|v| v.parse().expect("Invalid thread id"));
+    let client = Arc::new(twilight_http::client::ClientBuilder::new().build());
+    Some(parse_webhook(client, &url, thread))
+}
+
+pub fn parse_webhook(
+    client: Arc<twilight_http::Client>,
+    url: &str,
+    thread: Option<Id<ChannelMarker>>,
+) -> WebhookState {
     let (marker, webhook_token) =
-        twilight_util::link::webhook::parse(&url).expect("Error parsing webhook URL");
+        twilight_util::link::webhook::parse(url).expect("Error parsing webhook URL");
     let token = Arc::new(webhook_token.expect("Missing webhook token").to_string());
-    Some(WebhookState {
-        client: Arc::new(twilight_http::client::ClientBuilder::new().build()),
+    WebhookState {
+        client,
         marker,
         token,
         thread,
-    })
+    }
 }
 
 pub fn time_since_epoch(update_epoch_time: i64) -> Option<Duration> {